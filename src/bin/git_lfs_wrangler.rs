@@ -2,9 +2,21 @@
 #![allow(rustdoc::missing_crate_level_docs)] // it's an example
 
 use git_lfs_wrangler::lock::tag::Tag;
-use git_lfs_wrangler::{gui, lock::lockstore};
+use git_lfs_wrangler::lock::tag::notetag::NoteTag;
+use git_lfs_wrangler::lock::tag::expirytag::{self, ExpiryTag};
+use git_lfs_wrangler::lock::tag::grouptag;
+use git_lfs_wrangler::lock::tag::handofftag;
+use git_lfs_wrangler::lock::tag::hosttag;
+use git_lfs_wrangler::lock::tag::queuetag::{self, Enqueued};
+use git_lfs_wrangler::{gui, lock::lockstore, lock::watcher};
+use git_lfs_wrangler::lock::deadlock::WaitForGraph;
+use git_lfs_wrangler::lock::remotes::MergedView;
+use git_lfs_wrangler::lock::RepoPath;
+use git_lfs_wrangler::git;
 
 use git_lfs_wrangler::lock::lockstore::LockStore;
+use git_lfs_wrangler::lock::lockstore::caching_lockstore::{self, CachingLockStore};
+use git_lfs_wrangler::lock::lockstore::http_lockstore::{self, HttpLockStore};
 
 use clap::Parser;
 use std::process::ExitCode;
@@ -13,74 +25,263 @@ use std::process::ExitCode;
 /// A utility for managing git lfs lock contention
 struct Cli {
     /// Locks files with helpful annotations
-    #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
+    #[arg(short, long, num_args = 1..)]
     lock: Option<Vec<String>>,
 
+    /// Why you are locking the files, shown to everyone else alongside the locks
+    #[arg(long, requires = "lock")]
+    note: Option<String>,
+
+    /// The ticket the locked work is for
+    #[arg(long, requires = "lock")]
+    ticket: Option<String>,
+
+    /// Release the locks automatically once this long has passed, e.g. 2d or 4h
+    #[arg(long = "for", value_parser = humantime::parse_duration, requires = "lock")]
+    lock_for: Option<std::time::Duration>,
+
+    /// Lock the files together under this name: either all of them get locked or none do
+    #[arg(long, requires = "lock")]
+    group: Option<String>,
+
+    /// Release every lock you hold in a group
+    #[arg(long)]
+    release_group: Option<String>,
+
+    /// Enqueue for every file in a group
+    #[arg(long)]
+    queue_group: Option<String>,
+
     /// Unlocks files
-    #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
+    #[arg(short, long, num_args = 1..)]
     unlock: Option<Vec<String>>,
 
     /// Enqueue for a file
-    #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
+    #[arg(short, long, num_args = 1..)]
     queue: Option<Vec<String>>,
 
     /// Dequeue for a file
-    #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
+    #[arg(short, long, num_args = 1..)]
     dequeue: Option<Vec<String>>,
 
+    /// Release a lock straight to a colleague, who gets first claim on the file
+    #[arg(long, num_args = 2, value_names = ["FILE", "USER"])]
+    handoff: Option<Vec<String>>,
+
     /// List the repo's locks, alongside any helpful annotations
     #[arg(long)]
     list: bool,
+
+    /// Keep running, and grab the files you're queued for as soon as it's your turn
+    #[arg(long)]
+    watch: bool,
+
+    /// How often --watch checks the queues
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "15s", requires = "watch")]
+    interval: std::time::Duration,
+
+    /// Report which of your locks are on merged or deleted branches, without releasing anything
+    #[arg(long)]
+    stale_branches: bool,
+
+    /// Find users stuck waiting on each other's locks
+    #[arg(long)]
+    deadlocks: bool,
+
+    /// Also write who is waiting on whom as a Graphviz DOT file, or - for stdout
+    #[arg(long, requires = "deadlocks")]
+    dot: Option<String>,
+
+    /// The repo to work on instead of the one we're in. Give it more than once to open a tab for each in the gui
+    #[arg(long)]
+    repo: Vec<std::path::PathBuf>,
+
+    /// The remote whose lfs server holds the locks, instead of the default one. Give it more than once
+    /// with --list to see the locks of each remote side by side
+    #[arg(long)]
+    remote: Vec<String>,
+
+    /// Talk to the lfs lock api directly instead of running git-lfs for everything. Set
+    /// `git config lfs-wrangler.backend http` to always do so
+    #[arg(long)]
+    http: bool,
+}
+
+/* The store for a repo, talking to the lock api directly if asked to or configured to, and to git-lfs
+otherwise. None if we can't tell where the lock api is. One listing covers a whole batch of files
+instead of one per file */
+fn open_store(repo: &git::Repo, http: bool) -> Option<Box<dyn LockStore>> {
+    match http || http_lockstore::selected(repo) {
+        true => HttpLockStore::from_repo(repo).map(|store| CachingLockStore::new(*store, caching_lockstore::DEFAULT_TTL) as Box<dyn LockStore>),
+        false => Some(CachingLockStore::new(*lockstore::monothread_lockstore::MonothreadLockStore::for_repo(repo.clone()), caching_lockstore::DEFAULT_TTL)),
+    }
+}
+
+// Make the paths we were given relative to the repo root, so we can be run from anywhere in it
+fn repo_paths(paths: Vec<String>, repo: &git::Repo) -> Option<Vec<String>> {
+    let mut resolved = vec![];
+    for p in paths {
+        match RepoPath::from_user_in(&p, repo.root().to_path_buf()) {
+            Some(repo_path) => resolved.push(repo_path.into()),
+            None => {
+                println!("{} is not inside the repo", p);
+                return None;
+            }
+        }
+    }
+    Some(resolved)
+}
+
+// Hang the note and deadline off a freshly taken lock
+fn annotate(lock: &git_lfs_wrangler::lock::LfsLock, note: &Option<String>, ticket: &Option<String>, deadline: Option<chrono::DateTime<chrono::Utc>>, storage: &dyn LockStore) {
+    if note.is_some() || ticket.is_some() {
+        NoteTag::new(lock.id, note.clone().unwrap_or_default(), ticket.clone()).save(storage);
+    }
+    if let Some(deadline) = deadline {
+        ExpiryTag::new(lock.id, deadline).save(storage);
+    }
 }
 
-fn lock_files(locks: Vec<String>, storage: &dyn LockStore) -> bool {
+fn lock_files(locks: Vec<String>, note: Option<String>, ticket: Option<String>, lock_for: Option<std::time::Duration>, storage: &dyn LockStore) -> bool {
     let mut success = true;
+    let deadline = match lock_for.map(expirytag::deadline_from).transpose() {
+        Ok(deadline) => deadline,
+        Err(e) => {
+            println!("Failure: {}", e);
+            return false;
+        }
+    };
+    let raw_locks = match storage.get_raw_locks() {
+        Ok(raw_locks) => raw_locks,
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            return false;
+        }
+    };
     for lock in locks {
         print!("Locking {}...", lock);
-        if storage.lock_real_file(&lock).is_some() {
-            println!("Success!");
-        } else {
-            println!("Failure!");
-            success = false;
+        match storage.lock_real_file_with(&lock, &raw_locks) {
+            Ok(new_lock) => {
+                annotate(&new_lock, &note, &ticket, deadline, storage);
+                println!("Success!");
+            },
+            Err(e) => {
+                println!("Failure: {}", e);
+                success = false;
+            }
         }
     }
-    storage.update();
+    update(storage);
     success
 }
 
+// Run the store's upkeep and say what came of it
+fn update(storage: &dyn LockStore) {
+    let report = storage.update();
+    for released in &report.released {
+        println!("{}", released);
+    }
+    for (doing, e) in &report.errors {
+        println!("Error: {} failed: {}", doing, e);
+    }
+}
+
+fn lock_group(group: String, locks: Vec<String>, note: Option<String>, ticket: Option<String>, lock_for: Option<std::time::Duration>, storage: &dyn LockStore) -> bool {
+    let deadline = match lock_for.map(expirytag::deadline_from).transpose() {
+        Ok(deadline) => deadline,
+        Err(e) => {
+            println!("Failure: {}", e);
+            return false;
+        }
+    };
+    print!("Locking {} files as {}...", locks.len(), group);
+    let success = match grouptag::lock_group(storage, &group, &locks) {
+        Ok(new_locks) => {
+            for new_lock in &new_locks {
+                annotate(new_lock, &note, &ticket, deadline, storage);
+            }
+            println!("Success!");
+            true
+        },
+        Err((file, e)) => {
+            println!("Failure on {}: {}", file, e);
+            println!("Nothing was locked");
+            false
+        }
+    };
+    update(storage);
+    success
+}
+
+fn release_group(group: String, storage: &dyn LockStore) -> bool {
+    print!("Releasing group {}...", group);
+    let success = match grouptag::release_group(storage, &group) {
+        Ok(files) => {
+            println!("Released {}", files.join(", "));
+            true
+        },
+        Err(e) => {
+            println!("Failure: {}", e);
+            false
+        }
+    };
+    update(storage);
+    success
+}
+
+fn enqueue_group(group: String, storage: &dyn LockStore) -> bool {
+    println!("Enqueing for group {}...", group);
+    match grouptag::enqueue_group(storage, &group) {
+        Ok(results) => {
+            for (file, enqueued) in results {
+                match enqueued {
+                    Enqueued::Locked(_) => println!("{}: nobody held it, so it's locked for you!", file),
+                    Enqueued::Queued(position, len) | Enqueued::AlreadyQueued(position, len) => println!("{}: you are #{} of {}", file, position, len),
+                    Enqueued::AlreadyHeld => println!("{}: you already hold this lock!", file),
+                }
+            }
+            true
+        },
+        Err(e) => {
+            println!("Failure: {}", e);
+            false
+        }
+    }
+}
+
 fn unlock_files(locks: Vec<String>, storage: &dyn LockStore) -> bool {
     let mut success = true;
+    // Only needed for warnings, so carry on without them if the listing fails
+    let current = storage.get_locks().unwrap_or_default();
+    let here = storage.get_host();
     for lock in locks {
+        if let Some(warning) = current.iter().find(|l| l.file == lock).and_then(|l| hosttag::foreign_host_warning(l, &here)) {
+            println!("Warning: {}", warning);
+        }
         print!("Unlocking {}...", lock);
-        if storage.unlock_file(&lock) {
-            println!("Success!");
-        } else {
-            println!("Failure!");
-            success = false;
+        match storage.unlock_file(&lock) {
+            Ok(_) => println!("Success!"),
+            Err(e) => {
+                println!("Failure: {}", e);
+                success = false;
+            }
         }
     }
-    storage.update();
+    update(storage);
     success
 }
 
 fn enqueue_files(target_locks: Vec<String>, storage: &dyn LockStore) -> bool {
     let mut success = true;
-    let locks = storage.get_locks();
     for target_lock in target_locks {
         print!("Enqueing for {}...", target_lock);
-        match locks.iter().find(|lock| lock.file == target_lock) {
-            Some(lock) => {
-                // We are already enqueued for it
-                if lock.queue.contains(&git_lfs_wrangler::git::get_lfs_user()) {
-                    println!("Already enqueued!");
-                    continue;
-                }
-                let tag = git_lfs_wrangler::lock::tag::queuetag::for_lock(lock);
-                tag.save(storage);
-                println!("Success!");
-            }
-            None => {
-                println!("Lock does not exist!");
+        match queuetag::enqueue(storage, &target_lock) {
+            Ok(Enqueued::Locked(_)) => println!("Nobody held it, so it's locked for you!"),
+            Ok(Enqueued::Queued(position, len)) => println!("Success! You are #{} of {}", position, len),
+            Ok(Enqueued::AlreadyQueued(position, len)) => println!("Already enqueued! You are #{} of {}", position, len),
+            Ok(Enqueued::AlreadyHeld) => println!("You already hold this lock!"),
+            Err(e) => {
+                println!("Failure: {}", e);
                 success = false;
             }
         }
@@ -90,19 +291,29 @@ fn enqueue_files(target_locks: Vec<String>, storage: &dyn LockStore) -> bool {
 
 fn dequeue_files(target_locks: Vec<String>, storage: &dyn LockStore) -> bool {
     let mut success = true;
-    let locks = storage.get_locks();
+    let locks = match storage.get_locks() {
+        Ok(locks) => locks,
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            return false;
+        }
+    };
     for target_lock in target_locks {
         print!("Dequeing from {}...", target_lock);
         match locks.iter().find(|lock| lock.file == target_lock) {
             Some(lock) => {
-                if !lock.queue.contains(&git_lfs_wrangler::git::get_lfs_user()) {
+                if !lock.queue.contains(&storage.get_user()) {
                     success = false;
                     println!("Not enqueued for this lock!");
                     continue;
                 }
-                let tag = git_lfs_wrangler::lock::tag::queuetag::for_lock(lock);
-                tag.delete(storage);
-                println!("Success!");
+                match queuetag::dequeue(storage, lock.id, &storage.get_user()) {
+                    Ok(_) => println!("Success!"),
+                    Err(e) => {
+                        println!("Failure: {}", e);
+                        success = false;
+                    }
+                }
             }
             None => {
                 println!("Lock does not exist!");
@@ -113,39 +324,199 @@ fn dequeue_files(target_locks: Vec<String>, storage: &dyn LockStore) -> bool {
     success
 }
 
+fn handoff_file(file: &String, recipient: &String, storage: &dyn LockStore) -> bool {
+    print!("Handing {} off to {}...", file, recipient);
+    let success = match handofftag::handoff(storage, file, recipient) {
+        Ok(_) => {
+            println!("Success! Nobody else can lock it for the next {}", humantime::format_duration(handofftag::handoff_window().to_std().unwrap_or_default()));
+            true
+        },
+        Err(e) => {
+            println!("Failure: {}", e);
+            false
+        }
+    };
+    update(storage);
+    success
+}
+
+fn list_locks(storage: &dyn LockStore) -> bool {
+    match storage.get_locks() {
+        Ok(locks) => {
+            let user = storage.get_user();
+            for lock in locks.iter().filter(|lock| !git_lfs_wrangler::git::is_lock_test(lock)) {
+                println!("{}", lock);
+            }
+            for lock in &locks {
+                if let Some(position) = lock.queue_position(&user) {
+                    println!("You are #{} of {} in the queue for {}", position, lock.queue.len(), lock.file);
+                }
+            }
+            for lock in locks.iter().filter(|lock| lock.owner == user && expirytag::expires_soon(lock)) {
+                match expirytag::remaining(&lock.expires_at.unwrap()) {
+                    None => println!("Warning: your lock on {} has expired and will be released on the next update", lock.file),
+                    Some(left) => println!("Warning: your lock on {} expires in {}", lock.file, left),
+                }
+            }
+            true
+        },
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            false
+        }
+    }
+}
+
+// List the locks of several remotes of the repo next to each other, flagging where they disagree
+fn list_remotes(repo: &git::Repo, remotes: &[String], http: bool) -> bool {
+    let mut stores = vec![];
+    for remote in remotes {
+        match open_store(&repo.with_remote(remote), http) {
+            Some(store) => stores.push((remote.clone(), store)),
+            None => {
+                println!("Couldn't work out where the lfs server of {} is", remote);
+                return false;
+            }
+        }
+    }
+    let stores: Vec<(String, &dyn LockStore)> = stores.iter().map(|(remote, store)| (remote.clone(), &**store)).collect();
+    let view = match MergedView::from_stores(&stores) {
+        Ok(view) => view,
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            return false;
+        }
+    };
+    print!("{}", view);
+    match view.mismatched().len() {
+        0 => println!("Every remote agrees"),
+        n => println!("{} files are not locked on every remote", n),
+    }
+    true
+}
+
+fn watch_queues(interval: std::time::Duration, storage: &dyn LockStore) -> bool {
+    loop {
+        storage.invalidate();
+        match watcher::claim_queued(storage) {
+            // The bell gets the attention of whoever left this running in a corner
+            Ok(claimed) => for lock in claimed {
+                println!("\x07Your turn! {} is now locked for you", lock.file);
+            },
+            Err(e) => println!("Error: {}", e),
+        }
+        match watcher::queued_files(storage) {
+            Ok(files) if files.is_empty() => {
+                println!("Not queued for anything, done watching");
+                return true;
+            },
+            Ok(files) => println!("Waiting on {}", files.join(", ")),
+            Err(e) => println!("Error: {}", e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn report_stale_branches(storage: &dyn LockStore) -> bool {
+    let target = storage.merge_target();
+    let stale = match lockstore::lockstore::stale_branch_locks(storage, target.as_deref().unwrap_or("main")) {
+        Ok(stale) => stale,
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            return false;
+        }
+    };
+    for (lock, state) in &stale {
+        println!("{} (branch {} was {})", lock.file, lock.branch.clone().unwrap_or_default(), state);
+    }
+    match (target, stale.is_empty()) {
+        (_, true) => println!("None of your locks are on merged or deleted branches"),
+        (Some(_), false) => println!("These will be released on the next update"),
+        (None, false) => println!("Run `git config lfs-wrangler.releaseMergedInto main` to release these automatically"),
+    }
+    true
+}
+
+fn report_deadlocks(dot: Option<String>, storage: &dyn LockStore) -> bool {
+    let graph = match storage.get_locks() {
+        Ok(locks) => WaitForGraph::from_locks(&locks),
+        Err(e) => {
+            println!("Failed to fetch locks: {}", e);
+            return false;
+        }
+    };
+    let deadlocks = graph.deadlocks();
+    if deadlocks.is_empty() {
+        println!("Nobody is deadlocked");
+    }
+    for deadlock in deadlocks {
+        println!("Deadlock: {}", deadlock);
+    }
+    match dot.as_deref() {
+        None => true,
+        Some("-") => {
+            print!("{}", graph.to_dot());
+            true
+        },
+        Some(path) => match std::fs::write(path, graph.to_dot()) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Failed to write {}: {}", path, e);
+                false
+            }
+        },
+    }
+}
+
 fn main() -> ExitCode {
 
     let args = Cli::parse();
 
-    let storage = *lockstore::monothread_lockstore::MonothreadLockStore::new();
-
-    let mut cli_results = vec![];
-
-    cli_results.push(match args.lock {
-        Some(locks) => Some(lock_files(locks, &storage)),
-        None => None
-    });
-    cli_results.push(match args.unlock {
-        Some(locks) => Some(unlock_files(locks, &storage)),
-        None => None,
-    });
-    cli_results.push(match args.queue {
-        Some(locks) => Some(enqueue_files(locks, &storage)),
-        None => None
-    });
-    cli_results.push(match args.dequeue {
-        Some(locks) => Some(dequeue_files(locks, &storage)),
-        None => None
-    });
-    cli_results.push(match args.list {
-        true => {
-            for lock in storage.get_locks().iter().filter(|lock| !git_lfs_wrangler::git::is_lock_test(lock)) {
-                println!("{}", lock);
+    let mut repos = vec![];
+    for path in &args.repo {
+        match git::Repo::open(path, args.remote.first().map(|r| r.as_str())) {
+            Some(repo) => repos.push(repo),
+            None => {
+                println!("{} is not a git repo", path.display());
+                return ExitCode::FAILURE;
             }
-            Some(true)
-        },
-        false => None,
-    });
+        }
+    }
+    // The command line only ever works on one repo, the first we were given
+    let repo = match (repos.first(), args.remote.first()) {
+        (Some(repo), _) => repo.clone(),
+        (None, Some(remote)) => git::Repo::current().with_remote(remote),
+        (None, None) => git::Repo::current(),
+    };
+    let storage = match open_store(&repo, args.http) {
+        Some(storage) => storage,
+        None => {
+            println!("Couldn't work out where the lfs server of {} is", repo.name());
+            return ExitCode::FAILURE;
+        }
+    };
+    let storage = &*storage;
+
+    let cli_results = [
+        args.lock.map(|locks| match (repo_paths(locks, &repo), args.group) {
+            (None, _) => false,
+            (Some(locks), Some(group)) => lock_group(group, locks, args.note, args.ticket, args.lock_for, storage),
+            (Some(locks), None) => lock_files(locks, args.note, args.ticket, args.lock_for, storage),
+        }),
+        args.release_group.map(|group| release_group(group, storage)),
+        args.queue_group.map(|group| enqueue_group(group, storage)),
+        args.unlock.map(|locks| repo_paths(locks, &repo).is_some_and(|locks| unlock_files(locks, storage))),
+        args.queue.map(|locks| repo_paths(locks, &repo).is_some_and(|locks| enqueue_files(locks, storage))),
+        args.dequeue.map(|locks| repo_paths(locks, &repo).is_some_and(|locks| dequeue_files(locks, storage))),
+        args.handoff.map(|handoff| repo_paths(vec![handoff[0].clone()], &repo).is_some_and(|file| handoff_file(&file[0], &handoff[1], storage))),
+        args.list.then(|| match args.remote.len() {
+            0 | 1 => list_locks(storage),
+            _ => list_remotes(&repo, &args.remote, args.http),
+        }),
+        args.stale_branches.then(|| report_stale_branches(storage)),
+        args.deadlocks.then(|| report_deadlocks(args.dot, storage)),
+        args.watch.then(|| watch_queues(args.interval, storage)),
+    ];
 
     if cli_results.contains(&Some(false)) {
         return ExitCode::FAILURE;
@@ -153,8 +524,15 @@ fn main() -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
-    let mut opts = eframe::NativeOptions::default();
-    opts.follow_system_theme = false;
-    let _ = eframe::run_native("Git Lfs Wrangler", opts, Box::new(|cc| Ok(Box::new(gui::WranglerGui::new(cc)))));
+    let opts = eframe::NativeOptions {
+        follow_system_theme: false,
+        ..Default::default()
+    };
+    let http = args.http;
+    // Without --repo the gui still needs to know about --remote
+    if repos.is_empty() {
+        repos.push(repo);
+    }
+    let _ = eframe::run_native("Git Lfs Wrangler", opts, Box::new(move |cc| Ok(Box::new(gui::WranglerGui::new(cc, repos, http)))));
     ExitCode::SUCCESS
 }
\ No newline at end of file
@@ -0,0 +1,46 @@
+use git_lfs_wrangler::lockd::{LockDb, LockServer};
+
+use clap::Parser;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+/// A minimal Git LFS lock server. Point a repo's lfs.url at http://<addr>/ to use it
+///
+/// Credentials are NOT verified: whatever username a client sends in its Basic auth header is who it
+/// gets to be. Only run it where everyone who can reach it is trusted.
+struct Cli {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// File the lock table is persisted to
+    #[arg(short, long, default_value = "wrangler-locks.json")]
+    db: PathBuf,
+
+    /// A user allowed to force unlock other people's locks. Give it once per user; nobody can otherwise
+    #[arg(long)]
+    admin: Vec<String>,
+}
+
+fn main() -> ExitCode {
+    let args = Cli::parse();
+
+    let db = match LockDb::open(&args.db) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed to open {}: {:?}", args.db.to_string_lossy(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let server = match tiny_http::Server::http(&args.addr) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Failed to listen on {}: {}", args.addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("Serving lfs locks on http://{}/ from {}", args.addr, args.db.to_string_lossy());
+    LockServer::new(db).with_admins(args.admin).serve(&server);
+    ExitCode::SUCCESS
+}
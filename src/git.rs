@@ -1,62 +1,478 @@
-use std::process::Command;
-use std::os::windows::process::CommandExt;
-use std::sync::LazyLock;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::lock::lock;
 use crate::lock::lockstore::monothread_lockstore::MonothreadLockStore;
 use crate::lock::lockstore::LockStore;
 
+#[cfg(windows)]
 const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-fn get_user() -> String {
-    let out = Command::new("cmd").args(["/C", "git config --get user.name"]).creation_flags(CREATE_NO_WINDOW).output();
-    match out {
-        Err(_) => "".to_string(),
-        Ok(output) => {
-            let mut s = String::from_utf8_lossy(&output.stdout).to_string();
-            if s.ends_with('\n') {
-                s.pop();
-            }
-            s.replace(" ", "_")
-        }
+// Build a git invocation. On Windows we keep git from flashing a console window at the user
+pub fn git_command<I, S>(args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.creation_flags(CREATE_NO_WINDOW);
     }
+    cmd
+}
+
+// Run git with the given arguments and collect its output
+pub fn run_git<I, S>(args: I) -> io::Result<Output>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    git_command(args).output()
 }
 
-fn test_lock_string() -> String {
-    ["I___", get_user().as_str()].join("")
+// The trimmed output of a successful git invocation, None if it failed or had nothing to say
+fn output_line(out: io::Result<Output>) -> Option<String> {
+    match out {
+        Ok(output) if output.status.success() => {
+            let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if s.is_empty() { None } else { Some(s) }
+        },
+        _ => None,
+    }
 }
 
+// Left behind by versions that created a lock just to find out who we are
 pub fn is_lock_test(lock: &lock::LfsLock) -> bool {
     lock.file.starts_with("I___")
 }
 
-static LFS_USER: LazyLock<String> = LazyLock::new(|| {
-    let store = MonothreadLockStore::new();
-    match store.get_lock_file(&test_lock_string()) {
-        Some(lock) => lock.owner.clone(),
-        None => {
-            match store.lock_file_fetch(&test_lock_string()) {
-                None => String::from("UNKNOWN"),
-                Some(new_lock) => new_lock.owner.clone()
+// Pull our name out of `git lfs locks --verify --json`, which splits the locks into ours and theirs
+pub fn user_from_verify(stdout: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(stdout).ok()?;
+    json["ours"].as_array()?.iter().find_map(|lock| lock["owner"]["name"].as_str().map(|s| s.to_string()))
+}
+
+// Release the identity locks older versions left on the server in our name
+fn cleanup_identity_locks(store: &MonothreadLockStore, user: &str) {
+    if let Ok(locks) = store.get_raw_locks() {
+        for lock in locks.iter().filter(|lock| is_lock_test(lock) && lock.owner == *user) {
+            store.unlock_id_fast(lock.id);
+        }
+    }
+}
+
+// A work tree on disk, and the remote its locks live on. No remote means whichever one git-lfs picks
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Repo {
+    root: PathBuf,
+    remote: Option<String>,
+}
+
+// The repo we were run from, if we were run from one
+static CURRENT: LazyLock<Option<Repo>> = LazyLock::new(|| Repo::open(Path::new("."), None));
+
+// What this run knows of our name on a repo's lfs server
+#[derive(Clone, Debug)]
+enum LfsUser {
+    Known(String),
+    // Nothing could tell us when we last asked, so this is a guess until RESOLVE_RETRY has passed
+    Guessed(String, Instant),
+}
+
+// How long a failed attempt at our name stands before we ask the server again
+const RESOLVE_RETRY: Duration = Duration::from_secs(60);
+
+// The lfs server's name for us in every repo we've asked about
+static LFS_USERS: LazyLock<Mutex<HashMap<Repo, LfsUser>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl Repo {
+    // The repo path is in, talking to remote if given. None if path isn't in a repo
+    pub fn open(path: &Path, remote: Option<&str>) -> Option<Repo> {
+        let root = output_line(git_command(["rev-parse", "--show-toplevel"]).current_dir(path).output())?;
+        let mut repo = Repo { root: PathBuf::from(root), remote: None };
+        repo.remote = remote.map(|r| r.to_string()).or_else(|| repo.config("lfs-wrangler.remote"));
+        Some(repo)
+    }
+
+    // The repo we were run from, or just the current directory if that isn't one
+    pub fn current() -> Repo {
+        CURRENT.clone().unwrap_or_else(|| Repo { root: PathBuf::from("."), remote: None })
+    }
+
+    // The same repo, talking to another of its remotes
+    pub fn with_remote(&self, remote: &str) -> Repo {
+        Repo { root: self.root.clone(), remote: Some(remote.to_string()) }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn remote(&self) -> Option<&str> {
+        self.remote.as_deref()
+    }
+
+    // What to call the repo in front of the user
+    pub fn name(&self) -> String {
+        let name = match self.root.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => self.root.to_string_lossy().to_string(),
+        };
+        match &self.remote {
+            Some(remote) => format!("{} ({})", name, remote),
+            None => name,
+        }
+    }
+
+    // A git invocation run from the root of the work tree, which is what git-lfs takes lock paths to be relative to
+    pub fn command<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let mut cmd = git_command(args);
+        cmd.current_dir(&self.root);
+        cmd
+    }
+
+    pub fn run<I, S>(&self, args: I) -> io::Result<Output>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        self.command(args).output()
+    }
+
+    // `git lfs <args>`, pointed at our remote if we have one
+    pub fn run_lfs(&self, args: &[&str]) -> io::Result<Output> {
+        let mut cmd = self.command(["lfs"]);
+        cmd.args(args);
+        if let Some(remote) = &self.remote {
+            cmd.args(["--remote", remote]);
+        }
+        cmd.output()
+    }
+
+    // Read a single git config value, None if it isn't set
+    pub fn config(&self, key: &str) -> Option<String> {
+        output_line(self.run(["config", "--get", key]))
+    }
+
+    // Write a git config value to the repo's config, returning whether it stuck
+    pub fn set_config(&self, key: &str, value: &str) -> bool {
+        matches!(self.run(["config", key, value]), Ok(output) if output.status.success())
+    }
+
+    pub fn branch(&self) -> String {
+        output_line(self.run(["branch", "--show-current"])).unwrap_or_default()
+    }
+
+    // The remote we actually talk to, for config that's kept per remote
+    fn remote_name(&self) -> String {
+        self.remote.clone().unwrap_or_else(|| "origin".to_string())
+    }
+
+    // Work out the lfs api endpoint the same way git-lfs does: explicit config first, then the remote url
+    pub fn lfs_endpoint(&self) -> Option<String> {
+        let remote = self.remote_name();
+        if let Some(url) = self.config("lfs.url") {
+            return Some(url);
+        }
+        if let Some(url) = self.config(&format!("remote.{}.lfsurl", remote)) {
+            return Some(url);
+        }
+        self.config(&format!("remote.{}.url", remote)).and_then(|url| lfs_endpoint_from_remote(&url))
+    }
+
+    // Our git user.name, the way the old identity locks spelled it
+    fn git_user(&self) -> String {
+        self.config("user.name").unwrap_or_default().replace(" ", "_")
+    }
+
+    fn test_lock_string(&self) -> String {
+        ["I___", self.git_user().as_str()].join("")
+    }
+
+    // Where we remember the lfs server's name for us, one per remote
+    fn user_cache_key(&self) -> String {
+        format!("lfs-wrangler.{}.user", self.remote_name())
+    }
+
+    fn verify_user(&self) -> Option<String> {
+        match self.run_lfs(&["locks", "--verify", "--json"]) {
+            Ok(output) if output.status.success() => user_from_verify(&String::from_utf8_lossy(&output.stdout)),
+            _ => None,
+        }
+    }
+
+    /* The old way of finding out who we are: take a lock and see whose it is. Only for repos that opted in
+    with lfs-wrangler.identityLock, and the lock is given straight back */
+    fn identity_lock_user(&self, store: &MonothreadLockStore) -> Option<String> {
+        if self.config("lfs-wrangler.identityLock").as_deref() != Some("true") {
+            return None;
+        }
+        let lock = match store.get_lock_file(&self.test_lock_string()) {
+            Ok(Some(lock)) => lock,
+            _ => store.lock_file_fetch(&self.test_lock_string()).ok()?,
+        };
+        store.unlock_id_fast(lock.id);
+        Some(lock.owner)
+    }
+
+    // The username git hands the lfs server. Usually the same as the lock owner name, but only a guess
+    fn credential_user(&self) -> Option<String> {
+        get_credentials(&self.lfs_endpoint()?).map(|(username, _)| username)
+    }
+
+    /* Work out who the lfs server thinks we are without touching its state: the name remembered for this
+    remote, then the owner of any lock the server says is ours. None if the server had nothing to go by.
+    Whatever we learn is remembered */
+    fn resolve_lfs_user(&self) -> Option<String> {
+        if let Some(user) = self.config(&self.user_cache_key()) {
+            return Some(user);
+        }
+        let store = MonothreadLockStore::for_repo(self.clone());
+        let user = self.verify_user().or_else(|| self.identity_lock_user(&store))?;
+        self.set_config(&self.user_cache_key(), &user);
+        Some(user)
+    }
+
+    /* The lfs server's name for us, worked out once per repo. Until we know for sure it's a guess, which
+    is never written down, and is only asked about again once RESOLVE_RETRY has passed */
+    pub fn lfs_user(&self) -> String {
+        match LFS_USERS.lock().unwrap().get(self) {
+            Some(LfsUser::Known(user)) => return user.clone(),
+            Some(LfsUser::Guessed(user, at)) if at.elapsed() < RESOLVE_RETRY => return user.clone(),
+            _ => (),
+        }
+        // Not holding the cache while we ask, since that can take a while
+        match self.resolve_lfs_user() {
+            Some(user) => {
+                self.know_lfs_user(&user);
+                user
+            },
+            None => {
+                let guess = self.credential_user().unwrap_or_else(|| String::from("UNKNOWN"));
+                let mut users = LFS_USERS.lock().unwrap();
+                // Somebody may have learnt the real thing while we were guessing
+                match users.get(self) {
+                    Some(LfsUser::Known(user)) => user.clone(),
+                    _ => {
+                        users.insert(self.clone(), LfsUser::Guessed(guess.clone(), Instant::now()));
+                        guess
+                    },
+                }
+            },
+        }
+    }
+
+    // Remember the lfs server's name for us, e.g. the owner of a lock it just gave us
+    pub fn learn_lfs_user(&self, user: &str) {
+        if self.know_lfs_user(user) {
+            self.set_config(&self.user_cache_key(), user);
+        }
+    }
+
+    /* Settle on our name for the rest of the run, true if it's news. The first time we're sure of it,
+    however we came by it, release any identity locks older versions left on the server in that name */
+    fn know_lfs_user(&self, user: &str) -> bool {
+        let (first, news) = {
+            let mut users = LFS_USERS.lock().unwrap();
+            let (first, news) = match users.get(self) {
+                Some(LfsUser::Known(known)) => (false, known != user),
+                _ => (true, true),
+            };
+            if news {
+                users.insert(self.clone(), LfsUser::Known(user.to_string()));
+            }
+            (first, news)
+        };
+        if first {
+            cleanup_identity_locks(&MonothreadLockStore::for_repo(self.clone()), user);
+        }
+        news
+    }
+
+    /* Check a branch against the local and remote refs. Anything we can't be sure about is Live, since
+    getting this wrong means releasing somebody's lock out from under them */
+    pub fn branch_state(&self, branch: &str, target: &str) -> BranchState {
+        // A branch we've only just cut from the target looks merged, so leave the checked out one alone
+        if branch.is_empty() || branch == target || branch == self.branch() {
+            return BranchState::Live;
+        }
+        let refs = match self.run(["for-each-ref", "--format=%(refname)", "refs/heads", "refs/remotes"]) {
+            Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).to_string(),
+            _ => return BranchState::Live,
+        };
+        let local = format!("refs/heads/{}", branch);
+        let matching: Vec<&str> = refs.lines().filter(|r| {
+            *r == local || r.strip_prefix("refs/remotes/").and_then(|r| r.split_once('/')).is_some_and(|(_, b)| b == branch)
+        }).collect();
+        if matching.is_empty() {
+            return BranchState::Deleted;
+        }
+        // Every copy of the branch has to be in the target, so unpushed work keeps the lock
+        for r in matching {
+            match self.run(["merge-base", "--is-ancestor", r, target]).map(|output| output.status.code()) {
+                Ok(Some(0)) => (),
+                _ => return BranchState::Live,
             }
         }
+        BranchState::Merged
     }
-});
+}
+
+// The root of the work tree we were run from, None if we weren't run from one
+pub fn repo_root() -> Option<PathBuf> {
+    CURRENT.as_ref().map(|repo| repo.root.clone())
+}
 
 pub fn get_lfs_user() -> String {
-    (&*LFS_USER).clone()
+    Repo::current().lfs_user()
 }
 
 pub fn get_branch() -> String {
-    let out = Command::new("cmd").args(["/C", "git branch --show-current"]).creation_flags(CREATE_NO_WINDOW).output();
-    match out {
-        Err(_) => "".to_string(),
-        Ok(output) => {
-            let mut s = String::from_utf8_lossy(&output.stdout).to_string();
-            if s.ends_with('\n') {
-                s.pop();
-            }
-            s
+    Repo::current().branch()
+}
+
+// What became of the branch a lock was taken on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BranchState {
+    // Still being worked on, or we couldn't tell
+    Live,
+    // Fully merged into the target branch
+    Merged,
+    // No local or remote branch by that name any more
+    Deleted,
+}
+
+impl std::fmt::Display for BranchState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BranchState::Live => write!(f, "live"),
+            BranchState::Merged => write!(f, "merged"),
+            BranchState::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+pub fn get_branch_state(branch: &str, target: &str) -> BranchState {
+    Repo::current().branch_state(branch, target)
+}
+
+// Read a single git config value from the repo we were run from
+pub fn get_config(key: &str) -> Option<String> {
+    Repo::current().config(key)
+}
+
+// Turn a remote url into its default lfs endpoint, e.g. git@host:org/repo -> https://host/org/repo.git/info/lfs
+pub fn lfs_endpoint_from_remote(url: &str) -> Option<String> {
+    let url = url.trim_end_matches('/');
+    let base = if url.starts_with("https://") || url.starts_with("http://") {
+        url.to_string()
+    } else if let Some(rest) = url.strip_prefix("ssh://") {
+        let rest = rest.split_once('@').map(|(_, r)| r).unwrap_or(rest);
+        let (host, path) = rest.split_once('/')?;
+        let host = host.split(':').next()?;
+        format!("https://{}/{}", host, path)
+    } else if let Some((user_host, path)) = url.split_once(':') {
+        let host = user_host.split_once('@').map(|(_, h)| h).unwrap_or(user_host);
+        format!("https://{}/{}", host, path.trim_start_matches('/'))
+    } else {
+        return None;
+    };
+    if base.ends_with(".git") {
+        Some(format!("{}/info/lfs", base))
+    } else {
+        Some(format!("{}.git/info/lfs", base))
+    }
+}
+
+// Ask git's credential helpers for a username and password for the given url
+pub fn get_credentials(url: &str) -> Option<(String, String)> {
+    let (protocol, rest) = url.split_once("://")?;
+    let host = rest.split('/').next()?;
+    let mut child = git_command(["credential", "fill"])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let request = format!("protocol={}\nhost={}\n\n", protocol, host);
+    child.stdin.take()?.write_all(request.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8_lossy(&output.stdout).to_string();
+    let mut username = None;
+    let mut password = None;
+    for line in out.lines() {
+        match line.split_once('=') {
+            Some(("username", v)) => username = Some(v.to_string()),
+            Some(("password", v)) => password = Some(v.to_string()),
+            _ => (),
         }
     }
-}
\ No newline at end of file
+    Some((username?, password?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lfs_endpoint_from_remote_urls() {
+        assert_eq!(lfs_endpoint_from_remote("https://github.com/org/repo.git"), Some("https://github.com/org/repo.git/info/lfs".into()));
+        assert_eq!(lfs_endpoint_from_remote("https://github.com/org/repo"), Some("https://github.com/org/repo.git/info/lfs".into()));
+        assert_eq!(lfs_endpoint_from_remote("git@github.com:org/repo.git"), Some("https://github.com/org/repo.git/info/lfs".into()));
+        assert_eq!(lfs_endpoint_from_remote("ssh://git@example.com:2222/org/repo"), Some("https://example.com/org/repo.git/info/lfs".into()));
+        assert_eq!(lfs_endpoint_from_remote("/srv/repos/repo.git"), None);
+    }
+
+    #[test]
+    fn user_from_verify_reads_our_locks() {
+        let out = r#"{"ours":[{"id":"1","path":"a.uasset","owner":{"name":"alice"}}],"theirs":[{"id":"2","path":"b.uasset","owner":{"name":"bob"}}]}"#;
+        assert_eq!(user_from_verify(out), Some("alice".into()));
+        assert_eq!(user_from_verify(r#"{"ours":[],"theirs":[{"id":"2","path":"b.uasset","owner":{"name":"bob"}}]}"#), None);
+        assert_eq!(user_from_verify("not json"), None);
+    }
+
+    #[test]
+    fn repo_opens_from_anywhere_inside_it() {
+        let dir = std::env::temp_dir().join(format!("wrangler-repo-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Content")).unwrap();
+        assert!(git_command(["init", "-q"]).current_dir(&dir).status().unwrap().success());
+        let repo = Repo::open(&dir.join("Content"), Some("upstream")).unwrap();
+        assert_eq!(repo.root().canonicalize().unwrap(), dir.canonicalize().unwrap());
+        assert_eq!(repo.remote(), Some("upstream"));
+        assert_eq!(repo.remote_name(), "upstream");
+        assert_eq!(Repo::open(&dir, None).unwrap().remote_name(), "origin");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_guess_stands_until_the_user_is_learnt() {
+        let dir = std::env::temp_dir().join(format!("wrangler-user-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(git_command(["init", "-q"]).current_dir(&dir).status().unwrap().success());
+        let repo = Repo::open(&dir, None).unwrap();
+        // No lfs server to ask, so all we have is a guess, and one we don't keep asking about
+        assert_eq!(repo.lfs_user(), "UNKNOWN");
+        assert!(matches!(LFS_USERS.lock().unwrap().get(&repo), Some(LfsUser::Guessed(..))));
+        assert_eq!(repo.config("lfs-wrangler.origin.user"), None);
+
+        repo.learn_lfs_user("alice");
+        assert_eq!(repo.lfs_user(), "alice");
+        assert_eq!(repo.config("lfs-wrangler.origin.user"), Some("alice".into()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
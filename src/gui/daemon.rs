@@ -1,8 +1,12 @@
-use crate::lock::lockstore::LockStore;
-use crate::lock::{self, LfsLock};
-use crate::lock::tag::Tag;
+use crate::lock::lockstore::{LockError, LockResult, LockStore};
+use crate::lock::lockstore::caching_lockstore::{self, CachingLockStore};
+use crate::lock::lockstore::http_lockstore::{self, HttpLockStore};
+use crate::lock::{self, watcher, LfsLock};
+use crate::lock::tag::{grouptag, handofftag};
+use crate::lock::tag::queuetag::{self, Enqueued};
 
 use core::time;
+use std::cell::OnceCell;
 use std::sync::mpsc::{self, *};
 
 pub enum Command {
@@ -10,14 +14,26 @@ pub enum Command {
     UnlockID(u32),
     Update,
     FetchLocks,
-    Enqueue(u32),
+    Enqueue(String),
     Dequeue(u32),
+    ReleaseGroup(String),
+    EnqueueGroup(String),
+    Handoff(String, String),
+    SetNote(u32, String),
+    SetExpiry(u32, Option<chrono::DateTime<chrono::Utc>>),
+    ClaimQueued,
     UpdateCTX(egui::Context),
 }
 
 pub struct Daemon {
-    lock_chan: Receiver<Vec<LfsLock>>,
+    lock_chan: Receiver<LockResult<Vec<LfsLock>>>,
+    err_chan: Receiver<(String, LockError)>,
+    // Things worth telling the user that aren't failures
+    notice_chan: Receiver<String>,
     cmd_chan: Sender<Command>,
+    // Who the store acts as, resolved once the worker gets around to it
+    user_chan: Receiver<String>,
+    user: OnceCell<String>,
 }
 
 fn update_store(tx: Sender<Command>) {
@@ -30,65 +46,154 @@ fn update_store(tx: Sender<Command>) {
     }
 }
 
-fn run_store(cmd_rx: Receiver<Command>, lock_tx: Sender<Vec<LfsLock>>) {
-    let store = lock::lockstore::multithreaded_lockstore::MultithreadedLockStore::new();
-    let mut ctx = None;
+// Poke the store often enough that a queued user gets their file soon after it's released
+fn watch_queues(tx: Sender<Command>) {
+    while tx.send(Command::ClaimQueued).is_ok() {
+        std::thread::sleep(time::Duration::from_secs(30));
+    }
+}
+
+// Dequeue the current user from the lock with the given id
+fn dequeue(store: &dyn LockStore, id: u32) -> LockResult<()> {
+    let lock = store.get_lock_id(id)?.ok_or(LockError::NotFound)?;
+    queuetag::dequeue(store, lock.id, &store.get_user())
+}
+
+// What to tell the user about where enqueuing for a file left them
+fn enqueued_notice(file: &str, enqueued: &Enqueued) -> String {
+    match enqueued {
+        Enqueued::Locked(_) => format!("Nobody held {}, so it's locked for you", file),
+        Enqueued::Queued(position, len) | Enqueued::AlreadyQueued(position, len) => format!("You are #{} of {} in the queue for {}", position, len, file),
+        Enqueued::AlreadyHeld => format!("You already hold {}", file),
+    }
+}
+
+// Enqueue the current user for a file, letting them know if that got them the lock outright
+fn enqueue(store: &dyn LockStore, file: &str, notice_tx: &Sender<String>) -> LockResult<()> {
+    let enqueued = queuetag::enqueue(store, file)?;
+    let _ = notice_tx.send(enqueued_notice(file, &enqueued));
+    Ok(())
+}
+
+// Enqueue the current user for a whole group, with a notice per file
+fn enqueue_group(store: &dyn LockStore, group: &str, notice_tx: &Sender<String>) -> LockResult<()> {
+    for (file, enqueued) in grouptag::enqueue_group(store, group)? {
+        let _ = notice_tx.send(enqueued_notice(&file, &enqueued));
+    }
+    Ok(())
+}
+
+fn run_store(store: Box<dyn LockStore + Send>, cmd_rx: Receiver<Command>, lock_tx: Sender<LockResult<Vec<LfsLock>>>, err_tx: Sender<(String, LockError)>, notice_tx: Sender<String>, user_tx: Sender<String>) {
+    let _ = user_tx.send(store.get_user());
+    let mut ctx: Option<egui::Context> = None;
     while let Ok(cmd) = cmd_rx.recv() {
-        match cmd {
-            Command::Update => store.update(),
-            Command::UnlockID(id) => {
-                store.unlock_id(id);
-            },
-            Command::LockReal(file) => { store.lock_real_file(&file);},
-            Command::FetchLocks => lock_tx.send(store.get_locks()).unwrap(),
-            Command::Enqueue(id) => {
-                match store.get_lock_id(id) {
-                    Some(lock) => {
-                        let tag = lock::tag::queuetag::for_lock(&lock);
-                        tag.save(&*store);
-                    },
-                    _ => (),
+        let result = match cmd {
+            Command::Update => {
+                let report = store.update();
+                for released in &report.released {
+                    let _ = notice_tx.send(released.to_string());
                 }
-            }
-            Command::Dequeue(id) => {
-                match store.get_lock_id(id) {
-                    Some(lock) => {
-                        let tag = lock::tag::queuetag::for_lock(&lock);
-                        tag.delete(&*store);
-                    },
-                    _ => (),
+                for e in report.errors {
+                    let _ = err_tx.send(e);
                 }
-            }
-            Command::UpdateCTX(new_ctx) => ctx = Some(new_ctx),
+                Ok(())
+            },
+            Command::UnlockID(id) => store.unlock_id(id).map_err(|e| (format!("Unlocking lock {}", id), e)),
+            Command::LockReal(file) => store.lock_real_file(&file).map(|_| ()).map_err(|e| (format!("Locking {}", file), e)),
+            Command::FetchLocks => {
+                // Somebody wants to see the locks as they are, not as we remember them
+                store.invalidate();
+                lock_tx.send(store.get_locks()).unwrap();
+                Ok(())
+            },
+            Command::Enqueue(file) => enqueue(&*store, &file, &notice_tx).map_err(|e| (format!("Enqueuing for {}", file), e)),
+            Command::Dequeue(id) => dequeue(&*store, id).map_err(|e| (format!("Dequeuing from lock {}", id), e)),
+            Command::ReleaseGroup(group) => grouptag::release_group(&*store, &group).map(|_| ()).map_err(|e| (format!("Releasing group {}", group), e)),
+            Command::EnqueueGroup(group) => enqueue_group(&*store, &group, &notice_tx).map_err(|e| (format!("Enqueuing for group {}", group), e)),
+            Command::Handoff(file, recipient) => handofftag::handoff(&*store, &file, &recipient).map_err(|e| (format!("Handing {} off to {}", file, recipient), e)),
+            Command::SetNote(id, note) => lock::tag::notetag::set_note(&*store, id, &note, None).map_err(|e| (format!("Setting the note on lock {}", id), e)),
+            Command::SetExpiry(id, deadline) => lock::tag::expirytag::set_expiry(&*store, id, deadline).map_err(|e| (format!("Setting the expiry on lock {}", id), e)),
+            Command::ClaimQueued => match watcher::claim_queued(&*store) {
+                Ok(claimed) if claimed.is_empty() => Ok(()),
+                Ok(claimed) => {
+                    for lock in claimed {
+                        let _ = notice_tx.send(format!("Your turn! {} is now locked for you", lock.file));
+                    }
+                    if let Some(c) = &ctx {
+                        c.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(egui::UserAttentionType::Informational));
+                    }
+                    lock_tx.send(store.get_locks()).unwrap();
+                    Ok(())
+                },
+                Err(e) => Err(("Claiming queued files".to_string(), e)),
+            },
+            Command::UpdateCTX(new_ctx) => {
+                ctx = Some(new_ctx);
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            let _ = err_tx.send(e);
         }
-        match ctx {
-            Some(ref c) => c.request_repaint(),
-            _ => (),
+        if let Some(ref c) = ctx {
+            c.request_repaint();
         }
     }
 }
 
 pub fn spawn(spawn_update_thread: bool) -> Daemon {
+    spawn_for_repo(crate::git::Repo::current(), spawn_update_thread, false)
+}
+
+/* Run the daemon against the locks of a particular repo. It talks to the lock api directly if asked to
+with `http` or the repo's config, and git-lfs otherwise */
+pub fn spawn_for_repo(repo: crate::git::Repo, spawn_update_thread: bool, http: bool) -> Daemon {
+    let http_store = match http || http_lockstore::selected(&repo) {
+        true => HttpLockStore::from_repo(&repo),
+        false => None,
+    };
+    match http_store {
+        Some(store) => spawn_with_store(CachingLockStore::new(*store, caching_lockstore::DEFAULT_TTL), spawn_update_thread),
+        None => {
+            let store = *lock::lockstore::multithreaded_lockstore::MultithreadedLockStore::for_repo(repo);
+            spawn_with_store(CachingLockStore::new(store, caching_lockstore::DEFAULT_TTL), spawn_update_thread)
+        },
+    }
+}
+
+// Run the daemon against an arbitrary store, e.g. an in-memory one
+pub fn spawn_with_store(store: Box<dyn LockStore + Send>, spawn_update_thread: bool) -> Daemon {
     let (c_tx, c_rx) = mpsc::channel();
     let (l_tx, l_rx) = mpsc::channel();
+    let (e_tx, e_rx) = mpsc::channel();
+    let (n_tx, n_rx) = mpsc::channel();
+    let (u_tx, u_rx) = mpsc::channel();
     let update_tx = c_tx.clone();
+    let watch_tx = c_tx.clone();
     std::thread::spawn(move || {
-        run_store(c_rx, l_tx);
+        run_store(store, c_rx, l_tx, e_tx, n_tx, u_tx);
     });
     if spawn_update_thread {
         std::thread::spawn(move || {
             update_store(update_tx);
         });
+        std::thread::spawn(move || {
+            watch_queues(watch_tx);
+        });
     }
     Daemon{
-        lock_chan: l_rx, 
+        lock_chan: l_rx,
+        err_chan: e_rx,
+        notice_chan: n_rx,
         cmd_chan: c_tx,
+        user_chan: u_rx,
+        user: OnceCell::new(),
     }
 }
 
 impl Daemon {
     // Blocks until new locks are handed back
-    pub fn fetch_locks(&self) -> Vec<LfsLock> {
+    pub fn fetch_locks(&self) -> LockResult<Vec<LfsLock>> {
         self.cmd_chan.send(Command::FetchLocks).expect("Failed to send message!");
         self.lock_chan.recv().expect("Failed to read message!")
     }
@@ -101,11 +206,18 @@ impl Daemon {
         self.cmd_chan.send(Command::Update).expect("Failed to send message!");
     }
 
-    pub fn check_locks(&self) -> Option<Vec<LfsLock>> {
-        match self.lock_chan.try_recv() {
-            Ok(locks) => Some(locks),
-            Err(_) => None,
-        }
+    pub fn check_locks(&self) -> Option<LockResult<Vec<LfsLock>>> {
+        self.lock_chan.try_recv().ok()
+    }
+
+    // Drain the failures from commands we fired and forgot, each with a description of what was attempted
+    pub fn check_errors(&self) -> Vec<(String, LockError)> {
+        self.err_chan.try_iter().collect()
+    }
+
+    // Drain whatever the daemon wanted to tell the user
+    pub fn check_notices(&self) -> Vec<String> {
+        self.notice_chan.try_iter().collect()
     }
 
     pub fn unlock_id(&self, id: u32) {
@@ -116,15 +228,130 @@ impl Daemon {
         self.cmd_chan.send(Command::UpdateCTX(ctx)).expect("Failed to send message!");
     }
 
-    pub fn lock_real_file(&self, p: &String) {
-        self.cmd_chan.send(Command::LockReal(p.clone())).expect("Failed to send message!");
+    pub fn lock_real_file(&self, p: &str) {
+        self.cmd_chan.send(Command::LockReal(p.to_string())).expect("Failed to send message!");
     }
 
-    pub fn enqueue(&self, target_id: u32) {
-        self.cmd_chan.send(Command::Enqueue(target_id)).expect("Failed to send message!");
+    pub fn enqueue(&self, file: &str) {
+        self.cmd_chan.send(Command::Enqueue(file.to_string())).expect("Failed to send message!");
     }
 
     pub fn dequeue(&self, target_id: u32) {
         self.cmd_chan.send(Command::Dequeue(target_id)).expect("Failed to send message!");
     }
-}
\ No newline at end of file
+
+    pub fn release_group(&self, group: String) {
+        self.cmd_chan.send(Command::ReleaseGroup(group)).expect("Failed to send message!");
+    }
+
+    pub fn enqueue_group(&self, group: String) {
+        self.cmd_chan.send(Command::EnqueueGroup(group)).expect("Failed to send message!");
+    }
+
+    pub fn handoff(&self, file: &str, recipient: &str) {
+        self.cmd_chan.send(Command::Handoff(file.to_string(), recipient.to_string())).expect("Failed to send message!");
+    }
+
+    pub fn set_note(&self, target_id: u32, note: String) {
+        self.cmd_chan.send(Command::SetNote(target_id, note)).expect("Failed to send message!");
+    }
+
+    pub fn set_expiry(&self, target_id: u32, deadline: Option<chrono::DateTime<chrono::Utc>>) {
+        self.cmd_chan.send(Command::SetExpiry(target_id, deadline)).expect("Failed to send message!");
+    }
+
+    // The store's user, or None if the worker hasn't worked it out yet
+    pub fn user(&self) -> Option<&String> {
+        if self.user.get().is_none() {
+            if let Ok(user) = self.user_chan.try_recv() {
+                let _ = self.user.set(user);
+            }
+        }
+        self.user.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+    use crate::lock::lockstore::multithreaded_lockstore::MultithreadedLockStore;
+
+    #[test]
+    fn daemon_locks_and_enqueues() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        alice.lock_real_file("a.uasset").unwrap();
+        let daemon = spawn_with_store(bob, false);
+        let locks = daemon.fetch_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        daemon.enqueue(&locks[0].file);
+        daemon.lock_real_file("b.uasset");
+        daemon.enqueue("c.uasset");
+        let mut locks = daemon.fetch_locks().unwrap();
+        locks.sort_by(|l1, l2| l1.file.cmp(&l2.file));
+        assert_eq!(locks[0].queue, vec!["bob".to_string()]);
+        assert_eq!(locks[1].owner, "bob");
+        assert_eq!(locks[2].owner, "bob");
+        assert_eq!(daemon.check_notices(), vec![
+            "You are #1 of 1 in the queue for a.uasset".to_string(),
+            "Nobody held c.uasset, so it's locked for you".to_string(),
+        ]);
+        assert!(daemon.check_errors().is_empty());
+        assert_eq!(daemon.user(), Some(&"bob".to_string()));
+    }
+
+    #[test]
+    fn daemon_reports_failures() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        let daemon = spawn_with_store(bob, false);
+        daemon.lock_real_file("a.uasset");
+        daemon.unlock_id(lock.id);
+        let _ = daemon.fetch_locks();
+        let errors = daemon.check_errors();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].1, LockError::AlreadyLocked { owner: "alice".into() });
+        assert_eq!(errors[1].1, LockError::NotOwner { owner: "alice".into() });
+    }
+
+    #[test]
+    fn daemon_claims_queued_files() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        let daemon = spawn_with_store(bob, false);
+        daemon.enqueue(&lock.file);
+        // Make sure bob is in the queue before the file frees up
+        assert_eq!(daemon.fetch_locks().unwrap()[0].queue, vec!["bob".to_string()]);
+        alice.unlock_id(lock.id).unwrap();
+        daemon.cmd_chan.send(Command::ClaimQueued).unwrap();
+        let locks = daemon.lock_chan.recv().unwrap().unwrap();
+        assert_eq!(locks[0].owner, "bob");
+        assert_eq!(daemon.check_notices().last(), Some(&"Your turn! a.uasset is now locked for you".to_string()));
+    }
+
+    #[test]
+    fn daemon_leaves_and_claims_queues_on_the_gui_store() {
+        let alice = InMemoryLockStore::new("alice");
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        // Put together the way spawn_for_repo does it, over an in-memory server
+        let store = MultithreadedLockStore::over_in_memory(&alice.as_user("bob"));
+        let daemon = spawn_with_store(CachingLockStore::new(*store, caching_lockstore::DEFAULT_TTL), false);
+        daemon.enqueue(&lock.file);
+        assert_eq!(daemon.fetch_locks().unwrap()[0].queue, vec!["bob".to_string()]);
+        daemon.dequeue(lock.id);
+        assert!(daemon.fetch_locks().unwrap()[0].queue.is_empty());
+
+        daemon.enqueue(&lock.file);
+        assert_eq!(daemon.fetch_locks().unwrap()[0].queue, vec!["bob".to_string()]);
+        alice.unlock_id(lock.id).unwrap();
+        // The freed file only shows up once the daemon looks again
+        assert!(daemon.fetch_locks().unwrap().is_empty());
+        daemon.cmd_chan.send(Command::ClaimQueued).unwrap();
+        let locks = daemon.lock_chan.recv_timeout(time::Duration::from_secs(10)).expect("bob should have claimed the file").unwrap();
+        assert_eq!(locks[0].owner, "bob");
+        assert!(daemon.check_errors().is_empty());
+    }
+}
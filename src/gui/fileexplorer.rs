@@ -1,14 +1,20 @@
 use std::fs;
 use std::fs::DirEntry;
+use std::path::{Path, PathBuf};
 
+use crate::git;
+use crate::lock::RepoPath;
 
 use super::daemon::Daemon;
 
 pub struct FileExplorer {
-    selected_files: Vec<std::path::PathBuf>,
-    cwd: std::path::PathBuf,
-    locked_files: Vec<std::path::PathBuf>,
+    selected_files: Vec<RepoPath>,
+    // Paths are shown and locked relative to here, and we never browse above it
+    root: PathBuf,
+    cwd: PathBuf,
+    locked_files: Vec<RepoPath>,
     daemon: Daemon,
+    errors: Vec<String>,
 }
 
 impl Default for FileExplorer {
@@ -19,12 +25,25 @@ impl Default for FileExplorer {
 
 impl FileExplorer {
 
+    // Browse the repo we're in, or path if we aren't in one
     pub fn new(path: String) -> Self {
+        let root = git::repo_root().unwrap_or_else(|| Path::new(&path).to_path_buf());
+        FileExplorer::with_daemon(root, crate::gui::daemon::spawn(false))
+    }
+
+    // Browse a particular repo
+    pub fn for_repo(repo: &git::Repo, http: bool) -> Self {
+        FileExplorer::with_daemon(repo.root().to_path_buf(), crate::gui::daemon::spawn_for_repo(repo.clone(), false, http))
+    }
+
+    fn with_daemon(root: PathBuf, daemon: Daemon) -> Self {
         let mut fs = FileExplorer {
             selected_files: vec![],
-            cwd: std::path::Path::new(&path).to_path_buf(),
+            root: root.clone(),
+            cwd: root,
             locked_files: vec![],
-            daemon: crate::gui::daemon::spawn(false),
+            daemon,
+            errors: vec![],
         };
         fs.refresh_locks();
         fs
@@ -38,25 +57,33 @@ impl FileExplorer {
         self.daemon.refresh_locks();
     }
 
+    fn repo_path(&self, p: &Path) -> Option<RepoPath> {
+        RepoPath::resolve(p, &self.root, &self.root)
+    }
+
     fn render_dir_entry(&mut self, ui: &mut egui::Ui, f: &DirEntry) {
-        if self.selected_files.contains(&f.path()) {
+        let repo_path = match self.repo_path(&f.path()) {
+            Some(p) => p,
+            None => return,
+        };
+        if self.selected_files.contains(&repo_path) {
             return;
         }
         let os_name = f.file_name();
         let name = os_name.to_string_lossy();
         if f.path().is_dir() {
             ui.label("D");
-            if ui.monospace(name.to_owned()).clicked() {
+            if ui.monospace(name.clone()).clicked() {
                 self.cwd = f.path();
             }
-        } else if self.locked_files.contains(&f.path()) {
+        } else if self.locked_files.contains(&repo_path) {
             ui.label("L");
-            ui.monospace(name.to_owned());
+            ui.monospace(name.clone());
         }
         else {
             ui.label(" ");
             if ui.monospace(name).clicked() {
-                self.selected_files.push(f.path());
+                self.selected_files.push(repo_path);
             }
         }
         ui.end_row();
@@ -65,26 +92,24 @@ impl FileExplorer {
     // true means we did something with locking
     pub fn render(&mut self, ui: &mut egui::Ui) -> bool {
         match self.daemon.check_locks() {
-            Some(locks) => self.locked_files = locks.into_iter().map(|lock| {
-                let fixed_path = [".", &lock.file].join("/");
-                std::path::Path::new(&fixed_path).to_path_buf()
-            }).collect(),
+            Some(Ok(locks)) => self.locked_files = locks.into_iter().map(|lock| lock.file).collect(),
+            Some(Err(e)) => self.errors.push(format!("Fetching locks failed: {}", e)),
             None => (),
         }
+        for (action, e) in self.daemon.check_errors() {
+            self.errors.push(format!("{} failed: {}", action, e));
+        }
         let mut should_update_locks = false;
-        ui.label(&self.cwd.to_string_lossy().to_string());
+        ui.label(self.cwd.to_string_lossy().to_string());
         ui.separator();
         ui.horizontal(|ui| {
             egui::Grid::new("File Explorer Unselected").show(ui, |ui| {
-                match (self.cwd.parent(), self.cwd.to_string_lossy() == ".") {
-                    (Some(parent), false) => {
-                        ui.label("D");
-                        if ui.label("..").clicked() {
-                            self.cwd = parent.to_path_buf();
-                        }
-                        ui.end_row();
-                    },
-                    _ => (),
+                if let (Some(parent), false) = (self.cwd.parent(), self.cwd == self.root) {
+                    ui.label("D");
+                    if ui.label("..").clicked() {
+                        self.cwd = parent.to_path_buf();
+                    }
+                    ui.end_row();
                 }
                 if let Ok(entry) = fs::read_dir(&self.cwd) {
                     for e in entry {
@@ -99,11 +124,10 @@ impl FileExplorer {
             });
             ui.add(egui::Separator::default().vertical());
             egui::Grid::new("File Explorer Selected").show(ui, |ui| {
-                let mut paths_to_remove: Vec<std::path::PathBuf> = vec![];
+                let mut paths_to_remove: Vec<RepoPath> = vec![];
                 for file in &self.selected_files {
-                    let p = file.to_str().unwrap();
-                    if ui.monospace(p).clicked() {
-                        paths_to_remove.push(file.to_path_buf());
+                    if ui.monospace(file.as_str()).clicked() {
+                        paths_to_remove.push(file.clone());
                     }
                     ui.end_row();
                 }
@@ -115,12 +139,18 @@ impl FileExplorer {
         ui.separator();
         if ui.button("Lock files").clicked() {
             for file in &self.selected_files {
-                self.daemon.lock_real_file(&file.to_string_lossy().to_string());
+                self.daemon.lock_real_file(file);
             }
             self.refresh_locks();
             self.selected_files.clear();
             should_update_locks = true;
         }
+        for e in &self.errors {
+            ui.colored_label(egui::Color32::RED, e);
+        }
+        if !self.errors.is_empty() && ui.button("Dismiss errors").clicked() {
+            self.errors.clear();
+        }
         should_update_locks
     }
 }
\ No newline at end of file
@@ -6,35 +6,96 @@ use std::vec;
 use crate::gui::fileexplorer::FileExplorer;
 use crate::git;
 use crate::lock::LfsLock;
+use crate::lock::deadlock::{Deadlock, WaitForGraph};
+use crate::lock::lockstore::LockResult;
+use crate::lock::tag::{expirytag, hosttag};
 
 use super::daemon;
 
 type LockSortFunc = dyn FnMut(&LfsLock, &LfsLock) -> std::cmp::Ordering;
 
+// What the expiry picker offers, as hours from now
+const EXPIRY_CHOICES: [(&str, Option<u64>); 6] = [
+    ("1 hour", Some(1)),
+    ("4 hours", Some(4)),
+    ("1 day", Some(24)),
+    ("2 days", Some(48)),
+    ("1 week", Some(168)),
+    ("Never", None),
+];
+
 pub struct WranglerGui {
     locks: Vec<LfsLock>,
+    // Cycles in who is waiting on whom, worked out whenever the locks change
+    deadlocks: Vec<Deadlock>,
     lock_selection: HashMap<u32, bool>,
-    explorer: FileExplorer,
+    // Backing text for each lock's editable note
+    note_edits: HashMap<u32, String>,
+    // Index into EXPIRY_CHOICES
+    expiry_choice: usize,
+    // Who the selected locks get handed off to
+    handoff_to: String,
+    // One tab per repo we're watching, each with its own store, and the one being shown
+    tabs: Vec<RepoTab>,
+    active: usize,
+    // Backing text for the path of a repo to open another tab for
+    repo_to_open: String,
+    // Whether tabs talk to the lock api directly rather than through git-lfs
+    http: bool,
     lock_sort_fn: Box<LockSortFunc>,
     // Backing search texts
     file_search: String,
+    // Failures to show the user until they dismiss them
+    errors: Vec<String>,
+    // Same again for news that isn't bad
+    notices: Vec<String>,
+}
+
+// Everything we keep per repo. The daemons of tabs in the background carry on claiming queued files
+struct RepoTab {
+    repo: git::Repo,
     daemon: daemon::Daemon,
+    explorer: FileExplorer,
+}
+
+impl RepoTab {
+    fn new(repo: git::Repo, http: bool) -> Self {
+        RepoTab {
+            daemon: daemon::spawn_for_repo(repo.clone(), true, http),
+            explorer: FileExplorer::for_repo(&repo, http),
+            repo,
+        }
+    }
 }
 
 impl Default for WranglerGui {
     fn default() -> Self {
+        WranglerGui::with_repos(vec![git::Repo::current()], false)
+    }
+}
+
+impl WranglerGui {
+    fn with_repos(repos: Vec<git::Repo>, http: bool) -> Self {
         WranglerGui {
             locks: vec![],
+            deadlocks: vec![],
             lock_selection: HashMap::<u32, bool>::new(),
-            explorer: FileExplorer::new(".".into()),
+            note_edits: HashMap::new(),
+            expiry_choice: 2,
+            handoff_to: "".into(),
+            tabs: repos.into_iter().map(|repo| RepoTab::new(repo, http)).collect(),
+            http,
+            active: 0,
+            repo_to_open: "".into(),
             lock_sort_fn: Box::new(file_sort),
             file_search: "".into(),
-            daemon: daemon::spawn(true),
+            errors: vec![],
+            notices: vec![],
         }
     }
 }
 
-fn file_sort<'a, 'b>(l1: &'a LfsLock, l2: &'b LfsLock) -> std::cmp::Ordering {
+fn file_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
     l1.file.cmp(&l2.file)
 }
 fn owner_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
@@ -52,16 +113,92 @@ fn dir_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
 fn queue_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
     l1.queue.cmp(&l2.queue)
 }
+fn host_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
+    l1.host.cmp(&l2.host)
+}
+fn expiry_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
+    l1.expires_at.cmp(&l2.expires_at)
+}
+fn note_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
+    l1.note.cmp(&l2.note)
+}
+fn group_sort(l1: &LfsLock, l2: &LfsLock) -> std::cmp::Ordering {
+    l1.group.cmp(&l2.group)
+}
 
 impl WranglerGui {
-    pub fn new(cc: &eframe::CreationContext) -> Self {
-        let gui = Self::default();
-        gui.explorer.set_ctx(cc.egui_ctx.clone());
-        gui.daemon.set_ctx(cc.egui_ctx.clone());
-        gui.daemon.refresh_locks();
+    // Open a tab for each of repos, or just the repo we're in if there aren't any
+    pub fn new(cc: &eframe::CreationContext, repos: Vec<git::Repo>, http: bool) -> Self {
+        let repos = match repos.is_empty() {
+            true => vec![git::Repo::current()],
+            false => repos,
+        };
+        let gui = Self::with_repos(repos, http);
+        for tab in &gui.tabs {
+            tab.explorer.set_ctx(cc.egui_ctx.clone());
+            tab.daemon.set_ctx(cc.egui_ctx.clone());
+        }
+        gui.daemon().refresh_locks();
         gui
     }
 
+    fn daemon(&self) -> &daemon::Daemon {
+        &self.tabs[self.active].daemon
+    }
+
+    // Show another repo's locks. What's selected or being edited belongs to the old repo, so drop it
+    fn switch_tab(&mut self, index: usize) {
+        if index == self.active {
+            return;
+        }
+        self.active = index;
+        self.locks.clear();
+        self.deadlocks.clear();
+        self.lock_selection.clear();
+        self.note_edits.clear();
+        self.refresh_locks();
+    }
+
+    fn open_tab(&mut self, ctx: &egui::Context) {
+        let repo = match git::Repo::open(std::path::Path::new(&self.repo_to_open), None) {
+            Some(repo) => repo,
+            None => {
+                self.errors.push(format!("{} is not a git repo", self.repo_to_open));
+                return;
+            }
+        };
+        self.repo_to_open.clear();
+        match self.tabs.iter().position(|tab| tab.repo == repo) {
+            Some(index) => self.switch_tab(index),
+            None => {
+                let tab = RepoTab::new(repo, self.http);
+                tab.explorer.set_ctx(ctx.clone());
+                tab.daemon.set_ctx(ctx.clone());
+                self.tabs.push(tab);
+                self.switch_tab(self.tabs.len() - 1);
+            }
+        }
+    }
+
+    fn render_tabs(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let mut clicked = None;
+            for (i, tab) in self.tabs.iter().enumerate() {
+                if ui.selectable_label(i == self.active, tab.repo.name()).on_hover_text(tab.repo.root().to_string_lossy()).clicked() {
+                    clicked = Some(i);
+                }
+            }
+            if let Some(i) = clicked {
+                self.switch_tab(i);
+            }
+            ui.separator();
+            ui.add(egui::TextEdit::singleline(&mut self.repo_to_open).hint_text("Path to another repo").desired_width(200.0));
+            if ui.add_enabled(!self.repo_to_open.is_empty(), egui::Button::new("Open")).clicked() {
+                self.open_tab(ui.ctx());
+            }
+        });
+    }
+
     fn render_lock_headers(&mut self, ui: &mut egui::Ui) {
 
         ui.label("");
@@ -93,16 +230,33 @@ impl WranglerGui {
         if ui.label("Queue").clicked() {
             self.lock_sort_fn = Box::new(queue_sort);
         }
+        ui.add(Separator::default().vertical());
+        if ui.label("Host").clicked() {
+            self.lock_sort_fn = Box::new(host_sort);
+        }
+        ui.add(Separator::default().vertical());
+        if ui.label("Expires in").clicked() {
+            self.lock_sort_fn = Box::new(expiry_sort);
+        }
+        ui.add(Separator::default().vertical());
+        if ui.label("Group").clicked() {
+            self.lock_sort_fn = Box::new(group_sort);
+        }
+        ui.add(Separator::default().vertical());
+        if ui.label("Note").clicked() {
+            self.lock_sort_fn = Box::new(note_sort);
+        }
         ui.end_row();
     }
 
-    fn render_lock(check: &mut bool, lock: &LfsLock, ui: &mut egui::Ui) {
+    // Returns true when the user has finished editing the lock's note
+    fn render_lock(check: &mut bool, note: &mut String, lock: &LfsLock, user: Option<&String>, ui: &mut egui::Ui) -> bool {
         ui.checkbox(check, "");
-        ui.monospace(&lock.file);
+        ui.monospace(lock.file.as_str());
         ui.add(Separator::default().vertical());
         ui.monospace(&lock.owner);
         ui.add(Separator::default().vertical());
-        ui.monospace(&lock.id.to_string());
+        ui.monospace(lock.id.to_string());
         ui.add(Separator::default().vertical());
         match &lock.branch {
             None => ui.label("No associate branch"),
@@ -114,56 +268,119 @@ impl WranglerGui {
             Some(dir) => ui.monospace(dir),
         };
         ui.add(Separator::default().vertical());
-        if lock.queue.len() == 0 {
+        if lock.queue.is_empty() {
             ui.label("No queue detected");
         } else {
-            ui.monospace(format!("{:?}", lock.queue));
+            match user.and_then(|user| lock.queue_position(user)) {
+                Some(position) => ui.monospace(format!("{:?} (you are #{} of {})", lock.queue, position, lock.queue.len())),
+                None => ui.monospace(format!("{:?}", lock.queue)),
+            };
         }
+        ui.add(Separator::default().vertical());
+        match (&lock.host, &lock.os_user) {
+            (Some(host), Some(user)) => ui.monospace(format!("{}@{}", user, host)),
+            (Some(host), None) => ui.monospace(host),
+            _ => ui.label("Unknown host"),
+        };
+        ui.add(Separator::default().vertical());
+        match &lock.expires_at {
+            None => ui.label("Never"),
+            Some(deadline) if expirytag::expires_soon(lock) => ui.colored_label(egui::Color32::GOLD, expirytag::remaining(deadline).unwrap_or_else(|| "Expired".to_string())),
+            Some(deadline) => ui.monospace(expirytag::remaining(deadline).unwrap_or_else(|| "Expired".to_string())),
+        };
+        ui.add(Separator::default().vertical());
+        match &lock.group {
+            None => ui.label("No group"),
+            Some(group) => ui.monospace(group),
+        };
+        ui.add(Separator::default().vertical());
+        let edited = ui.horizontal(|ui| {
+            let resp = ui.add(egui::TextEdit::singleline(note).hint_text("Why is this locked?"));
+            if let Some(ticket) = &lock.ticket {
+                ui.monospace(format!("[{}]", ticket));
+            }
+            resp.lost_focus() && *note != lock.note.clone().unwrap_or_default()
+        }).inner;
         ui.end_row();
+        edited
     }
 
     fn render_locks(&mut self, ui: &mut egui::Ui) {
-        self.locks.sort_by( |l1, l2| (self.lock_sort_fn)(&l1, &l2));
+        self.locks.sort_by(|l1, l2| (self.lock_sort_fn)(l1, l2));
         let file_re = match regex::Regex::new(&self.file_search) {
             Err(_) => regex::Regex::new("").expect("Failed to compile empty regex somehow"),
             Ok(r) => r,
         };
+        let user = self.daemon().user().cloned();
         for lock in &self.locks {
-            match self.lock_selection.get_mut(&lock.id) {
-                None => (),
-                Some(b) => {
-                    if file_re.is_match(&lock.file) {
-                        Self::render_lock(b, &lock, ui)
-                    }
-                },
+            if !file_re.is_match(&lock.file) {
+                continue;
+            }
+            let edited = match (self.lock_selection.get_mut(&lock.id), self.note_edits.get_mut(&lock.id)) {
+                (Some(b), Some(note)) => Self::render_lock(b, note, lock, user.as_ref(), ui),
+                _ => false,
+            };
+            if edited {
+                self.daemon().set_note(lock.id, self.note_edits[&lock.id].clone());
+                self.daemon().refresh_locks();
             }
         }
     }
 
-    pub fn release_locks(&self) {
+    pub fn release_locks(&mut self) {
+        let here = hosttag::local_host();
         for (id, selected) in &self.lock_selection {
             if *selected {
-                self.daemon.unlock_id(*id);
+                if let Some(warning) = self.locks.iter().find(|lock| lock.id == *id).and_then(|lock| hosttag::foreign_host_warning(lock, &here)) {
+                    self.errors.push(warning);
+                }
+                self.daemon().unlock_id(*id);
             }
         }
-        self.daemon.update_locks();
-        self.daemon.refresh_locks();
+        self.daemon().update_locks();
+        self.daemon().refresh_locks();
     }
 
-    fn refresh_locks<'b>(&'b mut self) {
-        self.update_locks(self.daemon.fetch_locks());
+    fn refresh_locks(&mut self) {
+        self.update_locks(self.daemon().fetch_locks());
     }
 
-    fn update_locks(&mut self, new_locks: Vec<LfsLock>) {
+    fn update_locks(&mut self, new_locks: LockResult<Vec<LfsLock>>) {
+        let new_locks = match new_locks {
+            Ok(locks) => locks,
+            Err(e) => {
+                self.errors.push(format!("Fetching locks failed: {}", e));
+                return;
+            }
+        };
+        let old_notes: HashMap<u32, Option<String>> = self.locks.iter().map(|lock| (lock.id, lock.note.clone())).collect();
         self.locks = new_locks.into_iter().filter(|lock| !git::is_lock_test(lock)).collect();
         self.locks.sort_by(|l1, l2| (self.lock_sort_fn)(l1, l2));
+        self.deadlocks = WaitForGraph::from_locks(&self.locks).deadlocks();
         self.lock_selection.retain(|id, _| self.locks.iter().find(|lock| lock.id == *id).is_some());
+        self.note_edits.retain(|id, _| self.locks.iter().any(|lock| lock.id == *id));
         for lock in &self.locks {
-            if !self.lock_selection.contains_key(&lock.id) {
-                self.lock_selection.insert(lock.id, false);
+            self.lock_selection.entry(lock.id).or_insert(false);
+            match (old_notes.get(&lock.id), self.note_edits.contains_key(&lock.id)) {
+                // Same note on the server as before, so leave whatever the user is typing alone
+                (Some(old), true) if *old == lock.note => (),
+                _ => {
+                    self.note_edits.insert(lock.id, lock.note.clone().unwrap_or_default());
+                },
             }
         }
-        self.explorer.refresh_locks();
+        self.tabs[self.active].explorer.refresh_locks();
+    }
+
+    // The groups of the selected locks, each named once
+    fn selected_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self.locks.iter()
+            .filter(|lock| self.lock_selection.get(&lock.id) == Some(&true))
+            .filter_map(|lock| lock.group.clone())
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
     }
 
     fn clear_selection(&mut self) {
@@ -175,16 +392,41 @@ impl WranglerGui {
 
 impl eframe::App for WranglerGui {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        match self.daemon.check_locks() {
-            Some(locks) => {
-                self.update_locks(locks)
-            },
-            _ => (),
+        if let Some(locks) = self.daemon().check_locks() {
+            self.update_locks(locks)
         }
+        // Tabs in the background still report back, so say which repo they're about
+        let several = self.tabs.len() > 1;
+        for (i, tab) in self.tabs.iter().enumerate() {
+            let prefix = match several {
+                true => format!("[{}] ", tab.repo.name()),
+                false => "".into(),
+            };
+            if i != self.active {
+                // Stale by the time anyone switches to the tab, which refreshes anyway
+                while tab.daemon.check_locks().is_some() {}
+            }
+            for (action, e) in tab.daemon.check_errors() {
+                self.errors.push(format!("{}{} failed: {}", prefix, action, e));
+            }
+            self.notices.extend(tab.daemon.check_notices().into_iter().map(|notice| format!("{}{}", prefix, notice)));
+        }
+        // Keep the expiry countdowns ticking
+        ctx.request_repaint_after(std::time::Duration::from_secs(30));
+        if !self.deadlocks.is_empty() {
+            egui::TopBottomPanel::top("Deadlocks").show(ctx, |ui| {
+                for deadlock in &self.deadlocks {
+                    ui.colored_label(egui::Color32::RED, format!("Deadlock! {}", deadlock));
+                }
+            });
+        }
+        egui::TopBottomPanel::top("Repos").show(ctx, |ui| {
+            self.render_tabs(ui);
+        });
         egui::SidePanel::left("file explorer").show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
-                if self.explorer.render(ui) {
-                    self.daemon.refresh_locks();
+                if self.tabs[self.active].explorer.render(ui) {
+                    self.daemon().refresh_locks();
                 }
             });
         });
@@ -196,16 +438,13 @@ impl eframe::App for WranglerGui {
                 if ui.button("Enqueue for locks").clicked() {
                     for (id, sel) in &self.lock_selection {
                         if *sel {
-                            match self.locks.iter_mut().find(|lock| lock.id == *id) {
-                                Some(lock) => {
-                                    self.daemon.enqueue(lock.id);
-                                },
-                                None => (),
+                            if let Some(lock) = self.locks.iter().find(|lock| lock.id == *id) {
+                                self.daemon().enqueue(&lock.file);
                             }
                         }
                     }
                     self.clear_selection();
-                    self.daemon.refresh_locks();
+                    self.daemon().refresh_locks();
                 }
                 if ui.button("Sync locks").clicked() {
                     self.refresh_locks();
@@ -213,18 +452,86 @@ impl eframe::App for WranglerGui {
                 if ui.button("Dequeue for locks").clicked() {
                     for (id, sel) in &self.lock_selection {
                         if *sel {
-                            match self.locks.iter_mut().find(|lock| lock.id == *id) {
-                                Some(lock) => {
-                                    self.daemon.dequeue(lock.id);
-                                },
-                                None => (),
+                            if let Some(lock) = self.locks.iter().find(|lock| lock.id == *id) {
+                                self.daemon().dequeue(lock.id);
                             }
                         }
                     }
                     self.clear_selection();
-                    self.daemon.refresh_locks();
+                    self.daemon().refresh_locks();
                 }
-            })
+                ui.separator();
+                if ui.button("Release groups").on_hover_text("Release every lock in the selected locks' groups").clicked() {
+                    for group in self.selected_groups() {
+                        self.daemon().release_group(group);
+                    }
+                    self.clear_selection();
+                    self.daemon().refresh_locks();
+                }
+                if ui.button("Enqueue for groups").on_hover_text("Enqueue for every lock in the selected locks' groups").clicked() {
+                    for group in self.selected_groups() {
+                        self.daemon().enqueue_group(group);
+                    }
+                    self.clear_selection();
+                    self.daemon().refresh_locks();
+                }
+                ui.separator();
+                ui.add(egui::TextEdit::singleline(&mut self.handoff_to).hint_text("Colleague").desired_width(100.0));
+                if ui.add_enabled(!self.handoff_to.is_empty(), egui::Button::new("Hand off locks")).clicked() {
+                    for lock in self.locks.iter().filter(|lock| self.lock_selection.get(&lock.id) == Some(&true)) {
+                        self.daemon().handoff(&lock.file, &self.handoff_to);
+                    }
+                    self.clear_selection();
+                    self.daemon().refresh_locks();
+                }
+                ui.separator();
+                egui::ComboBox::from_id_source("expiry choice")
+                    .selected_text(EXPIRY_CHOICES[self.expiry_choice].0)
+                    .show_ui(ui, |ui| {
+                        for (i, (label, _)) in EXPIRY_CHOICES.iter().enumerate() {
+                            ui.selectable_value(&mut self.expiry_choice, i, *label);
+                        }
+                    });
+                if ui.button("Set expiry for locks").clicked() {
+                    match EXPIRY_CHOICES[self.expiry_choice].1.map(|hours| expirytag::deadline_from(std::time::Duration::from_secs(hours * 3600))).transpose() {
+                        Ok(deadline) => for (id, sel) in &self.lock_selection {
+                            if *sel {
+                                self.daemon().set_expiry(*id, deadline);
+                            }
+                        },
+                        Err(e) => self.errors.push(format!("Setting the expiry failed: {}", e)),
+                    }
+                    self.clear_selection();
+                    self.daemon().refresh_locks();
+                }
+            });
+            if let Some(user) = self.daemon().user() {
+                for lock in self.locks.iter().filter(|lock| lock.owner == *user && expirytag::expires_soon(lock)) {
+                    let warning = match expirytag::remaining(&lock.expires_at.unwrap()) {
+                        Some(left) => format!("Your lock on {} expires in {}", lock.file, left),
+                        None => format!("Your lock on {} has expired", lock.file),
+                    };
+                    ui.colored_label(egui::Color32::GOLD, warning);
+                }
+            }
+            if !self.notices.is_empty() {
+                ui.separator();
+                for notice in &self.notices {
+                    ui.label(notice);
+                }
+                if ui.button("Dismiss messages").clicked() {
+                    self.notices.clear();
+                }
+            }
+            if !self.errors.is_empty() {
+                ui.separator();
+                for e in &self.errors {
+                    ui.colored_label(egui::Color32::RED, e);
+                }
+                if ui.button("Dismiss errors").clicked() {
+                    self.errors.clear();
+                }
+            }
         });
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::both().show(ui, |ui| {
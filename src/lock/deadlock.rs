@@ -0,0 +1,198 @@
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::lock::LfsLock;
+
+/* Who is waiting on whom. An edge from a waiter to a holder exists for every file the holder has
+locked that the waiter is queued for */
+#[derive(Debug, Default)]
+pub struct WaitForGraph {
+    // waiter -> holder -> the files the waiter wants from the holder
+    edges: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
+}
+
+// A ring of users each waiting on a file the next one holds, so nobody ever gets anything
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deadlock {
+    // users[i] waits on users[i + 1] for files[i], wrapping around at the end
+    pub users: Vec<String>,
+    pub files: Vec<String>,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, user) in self.users.iter().enumerate() {
+            let next = &self.users[(i + 1) % self.users.len()];
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} waits on {} for {}", user, next, self.files[i])?;
+        }
+        Ok(())
+    }
+}
+
+impl WaitForGraph {
+    pub fn from_locks(locks: &[LfsLock]) -> Self {
+        let mut graph = WaitForGraph::default();
+        for lock in locks {
+            for waiter in lock.queue.iter().filter(|waiter| **waiter != lock.owner) {
+                graph.edges.entry(waiter.clone()).or_default()
+                    .entry(lock.owner.clone()).or_default()
+                    .insert(lock.file.to_string());
+            }
+        }
+        graph
+    }
+
+    fn successors(&self, user: &String) -> Vec<&String> {
+        self.edges.get(user).map(|holders| holders.keys().collect()).unwrap_or_default()
+    }
+
+    // Tarjan's strongly connected components. Any component with more than one user holds a cycle
+    fn components(&self) -> Vec<BTreeSet<String>> {
+        struct State<'a> {
+            index: BTreeMap<&'a String, usize>,
+            low: BTreeMap<&'a String, usize>,
+            stack: Vec<&'a String>,
+            on_stack: BTreeSet<&'a String>,
+            components: Vec<BTreeSet<String>>,
+        }
+        fn visit<'a>(graph: &'a WaitForGraph, user: &'a String, state: &mut State<'a>) {
+            let i = state.index.len();
+            state.index.insert(user, i);
+            state.low.insert(user, i);
+            state.stack.push(user);
+            state.on_stack.insert(user);
+            for next in graph.successors(user) {
+                if !state.index.contains_key(next) {
+                    visit(graph, next, state);
+                    let low = state.low[user].min(state.low[next]);
+                    state.low.insert(user, low);
+                } else if state.on_stack.contains(next) {
+                    let low = state.low[user].min(state.index[next]);
+                    state.low.insert(user, low);
+                }
+            }
+            if state.low[user] == state.index[user] {
+                let mut component = BTreeSet::new();
+                while let Some(member) = state.stack.pop() {
+                    state.on_stack.remove(member);
+                    component.insert(member.clone());
+                    if member == user {
+                        break;
+                    }
+                }
+                state.components.push(component);
+            }
+        }
+        let mut state = State {
+            index: BTreeMap::new(),
+            low: BTreeMap::new(),
+            stack: vec![],
+            on_stack: BTreeSet::new(),
+            components: vec![],
+        };
+        for user in self.edges.keys() {
+            if !state.index.contains_key(user) {
+                visit(self, user, &mut state);
+            }
+        }
+        state.components
+    }
+
+    // One cycle out of every knot of users that are stuck waiting on each other
+    pub fn deadlocks(&self) -> Vec<Deadlock> {
+        let mut deadlocks = vec![];
+        for component in self.components().into_iter().filter(|c| c.len() > 1) {
+            // Walk from the first user, staying inside the component, until we come back around
+            let mut path: Vec<String> = vec![component.first().unwrap().clone()];
+            loop {
+                let current = path.last().unwrap();
+                let next = self.successors(current).into_iter().find(|next| component.contains(*next)).unwrap().clone();
+                if let Some(start) = path.iter().position(|user| *user == next) {
+                    path.drain(..start);
+                    break;
+                }
+                path.push(next);
+            }
+            let files = path.iter().enumerate().map(|(i, user)| {
+                let next = &path[(i + 1) % path.len()];
+                self.edges[user][next].iter().cloned().collect::<Vec<String>>().join(", ")
+            }).collect();
+            deadlocks.push(Deadlock { users: path, files });
+        }
+        deadlocks
+    }
+
+    // Graphviz source for the graph, with users stuck in a deadlock drawn in red
+    pub fn to_dot(&self) -> String {
+        let stuck: BTreeSet<String> = self.deadlocks().into_iter().flat_map(|d| d.users).collect();
+        let mut dot = String::from("digraph waits_for {\n");
+        for user in &stuck {
+            dot.push_str(&format!("    {} [color=red];\n", dot_quote(user)));
+        }
+        for (waiter, holders) in &self.edges {
+            for (holder, files) in holders {
+                // One file per line of the label. \n is DOT's own line break, so it stays unescaped
+                let label = files.iter().map(|file| dot_escape(file)).collect::<Vec<String>>().join("\\n");
+                dot.push_str(&format!("    {} -> {} [label=\"{}\"];\n", dot_quote(waiter), dot_quote(holder), label));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// DOT strings only give " and \ special meaning, and take anything else as is
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", dot_escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(file: &str, owner: &str, queue: &[&str]) -> LfsLock {
+        LfsLock {
+            file: file.into(),
+            owner: owner.to_string(),
+            queue: queue.iter().map(|q| q.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_cycles_and_ignores_chains() {
+        let graph = WaitForGraph::from_locks(&[
+            lock("x", "alice", &["bob"]),
+            lock("y", "bob", &["alice", "carol"]),
+            lock("z", "carol", &["dave"]),
+        ]);
+        let deadlocks = graph.deadlocks();
+        assert_eq!(deadlocks, vec![Deadlock { users: vec!["alice".into(), "bob".into()], files: vec!["y".into(), "x".into()] }]);
+        assert_eq!(deadlocks[0].to_string(), "alice waits on bob for y, bob waits on alice for x");
+        let dot = graph.to_dot();
+        assert!(dot.contains("\"alice\" [color=red];"));
+        assert!(dot.contains("\"dave\" -> \"carol\" [label=\"z\"];"));
+        assert!(!dot.contains("\"dave\" [color=red]"));
+    }
+
+    #[test]
+    fn dot_labels_list_every_file() {
+        let dot = WaitForGraph::from_locks(&[
+            lock("Maps/Level \"1\".umap", "alice", &["CORP\\björn"]),
+            lock("Maps/Level 2.umap", "alice", &["CORP\\björn"]),
+        ]).to_dot();
+        assert!(dot.contains(r#""CORP\\björn" -> "alice" [label="Maps/Level \"1\".umap\nMaps/Level 2.umap"];"#));
+    }
+
+    #[test]
+    fn no_queue_no_deadlock() {
+        assert!(WaitForGraph::from_locks(&[lock("x", "alice", &[]), lock("y", "bob", &["carol"])]).deadlocks().is_empty());
+    }
+}
@@ -1,36 +1,79 @@
 use core::fmt;
+use std::collections::BTreeMap;
 
+use crate::lock::RepoPath;
+
+#[derive(Clone, Debug, Default)]
 pub struct LfsLock {
-    pub file: String,
+    pub file: RepoPath,
     pub owner: String,
     pub id: u32,
+    pub locked_at: Option<String>,
     pub branch: Option<String>,
     pub dir: Option<String>,
+    // The machine the lock was taken from, and who was logged in to it
+    pub host: Option<String>,
+    pub os_user: Option<String>,
+    // Who is waiting for the lock, head of the queue first
     pub queue: Vec<String>,
+    // When each user in the queue joined it, None for tags that predate timestamps
+    pub queued_at: BTreeMap<String, Option<chrono::DateTime<chrono::Utc>>>,
+    // Why the owner is holding the lock, and what for
+    pub note: Option<String>,
+    pub ticket: Option<String>,
+    // When the lock gets released whether or not the owner is done with it
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // The named set of files the lock was taken alongside
+    pub group: Option<String>,
+    // Anything tags outside this crate want to hang on the lock, keyed by the tag's choosing
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl LfsLock {
-    pub fn from_line(line: String) -> Option<LfsLock> {
-        let fields: Vec<&str> = line.split_whitespace().filter(|&s| !s.is_empty()).collect();
-        match fields.len() {
-            3 => Some(LfsLock::new(fields[0].to_string(), fields[1].to_string(), fields[2].to_string(), None)),
-            _ => None,
+    // Build a lock from a single entry of `git lfs locks --json` or `git lfs lock --json`
+    pub fn from_json(entry: &serde_json::Value) -> Result<LfsLock, String> {
+        let id = match &entry["id"] {
+            serde_json::Value::String(s) => s.parse::<u32>().map_err(|_| format!("invalid lock id {:?}", s))?,
+            serde_json::Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()).ok_or(format!("invalid lock id {}", n))?,
+            _ => return Err(format!("lock entry has no id: {}", entry)),
+        };
+        let file = entry["path"].as_str().ok_or(format!("lock entry has no path: {}", entry))?;
+        let owner = entry["owner"]["name"].as_str().ok_or(format!("lock entry has no owner: {}", entry))?;
+        Ok(LfsLock {
+            file: RepoPath::new(file),
+            owner: owner.to_string(),
+            id,
+            locked_at: entry["locked_at"].as_str().map(|s| s.to_string()),
+            ..Default::default()
+        })
+    }
+
+    // The lfs api representation of the lock, the inverse of from_json
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut entry = serde_json::json!({
+            "id": self.id.to_string(),
+            "path": self.file.as_str(),
+            "owner": { "name": self.owner },
+        });
+        if let Some(locked_at) = &self.locked_at {
+            entry["locked_at"] = serde_json::json!(locked_at);
         }
+        entry
+    }
+
+    // 1-based place of the user in the queue, None if they aren't in it
+    pub fn queue_position(&self, user: &String) -> Option<usize> {
+        self.queue.iter().position(|owner| owner == user).map(|i| i + 1)
     }
 
     pub fn new(file: String, owner: String, id: String, branch: Option<String>) -> Self {
-        let _ = id.strip_prefix("ID:");
-        let id_num = match id.trim_start_matches("ID:").parse::<u32>() {
-            Ok(val) => val,
-            Err(_) => 0,
-        };
+        let id_num = id.trim_start_matches("ID:").parse::<u32>().unwrap_or_default();
         LfsLock{
-            file: file,
-            owner: owner,
+            file: file.into(),
+            owner,
             id: id_num,
-            branch: branch,
-            dir: None,
-            queue: vec![],
+            branch,
+            ..Default::default()
         }
     }
 }
@@ -39,7 +82,55 @@ impl fmt::Display for LfsLock {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.branch {
             Some(branch_name) => write!(f, "file: {}; owner: {}; id: {}; branch: {}; queue: {:?}", self.file, self.owner, self.id, branch_name, self.queue),
-            None => write!(f, "file: {}; owner: {}; id: {}; branch: {}; queue: {:?}", self.file, self.owner, self.id, "None detected", self.queue),
+            None => write!(f, "file: {}; owner: {}; id: {}; branch: None detected; queue: {:?}", self.file, self.owner, self.id, self.queue),
+        }?;
+        match (&self.host, &self.os_user) {
+            (Some(host), Some(user)) => write!(f, "; host: {}@{}", user, host)?,
+            (Some(host), None) => write!(f, "; host: {}", host)?,
+            _ => (),
+        };
+        match (&self.note, &self.ticket) {
+            (Some(note), Some(ticket)) => write!(f, "; note: {} [{}]", note, ticket)?,
+            (Some(note), None) => write!(f, "; note: {}", note)?,
+            (None, Some(ticket)) => write!(f, "; note: [{}]", ticket)?,
+            (None, None) => (),
+        };
+        if let Some(deadline) = &self.expires_at {
+            write!(f, "; expires: {}", deadline.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))?;
         }
+        if let Some(group) = &self.group {
+            write!(f, "; group: {}", group)?;
+        }
+        for (key, value) in &self.metadata {
+            write!(f, "; {}: {}", key, value)?;
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_json_keeps_spaces_in_paths() {
+        let entry = serde_json::json!({
+            "id": "12",
+            "path": "Content/My Map/Level 03.umap",
+            "owner": { "name": "alice" },
+            "locked_at": "2024-01-02T03:04:05Z",
+        });
+        let lock = LfsLock::from_json(&entry).unwrap();
+        assert_eq!(lock.id, 12);
+        assert_eq!(lock.file, "Content/My Map/Level 03.umap");
+        assert_eq!(lock.owner, "alice");
+        assert_eq!(lock.locked_at, Some("2024-01-02T03:04:05Z".to_string()));
+    }
+
+    #[test]
+    fn from_json_rejects_malformed_entries() {
+        assert!(LfsLock::from_json(&serde_json::json!({"id": "x", "path": "a", "owner": {"name": "b"}})).is_err());
+        assert!(LfsLock::from_json(&serde_json::json!({"id": "1", "owner": {"name": "b"}})).is_err());
+        assert!(LfsLock::from_json(&serde_json::json!({"id": "1", "path": "a"})).is_err());
+    }
+}
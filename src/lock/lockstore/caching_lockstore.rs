@@ -0,0 +1,246 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::git::BranchState;
+use crate::lock::LfsLock;
+
+use super::{LockResult, LockStore};
+use super::lockstore::UpdateReport;
+
+// Long enough to cover a bulk operation, short enough that nobody acts on a listing they'd call stale
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10);
+
+/* Wraps another store and remembers its lock listing for a while, so a bulk operation that looks the
+locks up over and over only lists them once. Our own locks and unlocks are applied to the remembered
+listing as they succeed; anything else that changes on the server shows up once the ttl runs out, or
+straight away after invalidate() */
+pub struct CachingLockStore<S: LockStore> {
+    inner: S,
+    ttl: Duration,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    // The last raw listing, and when we fetched it
+    listing: Option<(Instant, Vec<LfsLock>)>,
+    // Bumped by every change we make, so a listing fetched across one of them can tell it's out of date
+    generation: u64,
+}
+
+impl<S: LockStore> CachingLockStore<S> {
+    pub fn new(inner: S, ttl: Duration) -> Box<Self> {
+        Box::new(CachingLockStore {
+            inner,
+            ttl,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // Apply a change to the remembered listing, if we have one
+    fn edit_cache(&self, edit: impl FnOnce(&mut Vec<LfsLock>)) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.generation += 1;
+        if let Some((_, locks)) = cache.listing.as_mut() {
+            edit(locks);
+        }
+    }
+
+    // Keep the remembered listing in step with a change of ours. If it failed, the server knows better than we do
+    fn track<T>(&self, result: LockResult<T>, edit: impl FnOnce(&mut Vec<LfsLock>, &T)) -> LockResult<T> {
+        match &result {
+            Ok(value) => self.edit_cache(|locks| edit(locks, value)),
+            Err(_) => self.invalidate(),
+        }
+        result
+    }
+}
+
+impl<S: LockStore> LockStore for CachingLockStore<S> {
+
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
+        let generation = {
+            let cache = self.cache.lock().unwrap();
+            if let Some((fetched, locks)) = cache.listing.as_ref() {
+                if fetched.elapsed() < self.ttl {
+                    return Ok(locks.clone());
+                }
+            }
+            cache.generation
+        };
+        // Not holding the cache while we ask, since that can take a while
+        let locks = self.inner.get_raw_locks()?;
+        let mut cache = self.cache.lock().unwrap();
+        // A change of ours landed while we were asking, and may be missing from what we got back
+        if cache.generation == generation {
+            cache.listing = Some((Instant::now(), locks.clone()));
+        }
+        Ok(locks)
+    }
+
+    fn invalidate(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.generation += 1;
+        cache.listing = None;
+    }
+
+    fn update(&self) -> UpdateReport {
+        // update() works on the inner store, so neither side of it can trust what we remember
+        self.invalidate();
+        let report = self.inner.update();
+        self.invalidate();
+        report
+    }
+
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
+        self.track(self.inner.lock_file_fetch(p), |locks, lock| locks.push(lock.clone()))
+    }
+
+    fn unlock_file(&self, p: &str) -> LockResult<()> {
+        self.track(self.inner.unlock_file(p), |locks, _| locks.retain(|lock| lock.file != *p))
+    }
+
+    fn unlock_id(&self, id: u32) -> LockResult<()> {
+        self.track(self.inner.unlock_id(id), |locks, _| locks.retain(|lock| lock.id != id))
+    }
+
+    fn get_user(&self) -> String {
+        self.inner.get_user()
+    }
+
+    fn get_branch(&self) -> String {
+        self.inner.get_branch()
+    }
+
+    fn get_host(&self) -> String {
+        self.inner.get_host()
+    }
+
+    fn get_dir(&self) -> String {
+        self.inner.get_dir()
+    }
+
+    fn get_config(&self, key: &str) -> Option<String> {
+        self.inner.get_config(key)
+    }
+
+    fn branch_state(&self, branch: &str, target: &str) -> BranchState {
+        self.inner.branch_state(branch, target)
+    }
+
+    fn merge_target(&self) -> Option<String> {
+        self.inner.merge_target()
+    }
+
+    fn records_mode(&self) -> bool {
+        self.inner.records_mode()
+    }
+
+    fn records_os_user(&self) -> bool {
+        self.inner.records_os_user()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::fake_git_backend::{FakeGitBackend, GitCall};
+    use crate::lock::lockstore::git_backend::GitOutput;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+    use crate::lock::lockstore::monothread_lockstore::MonothreadLockStore;
+    use std::sync::mpsc;
+
+    // Takes its snapshot of the server, then the first time round waits to be told to hand it over
+    struct SlowLister {
+        inner: Box<InMemoryLockStore>,
+        gate: Mutex<Option<(mpsc::Sender<()>, mpsc::Receiver<()>)>>,
+    }
+
+    impl LockStore for SlowLister {
+        fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
+            let locks = self.inner.get_raw_locks();
+            if let Some((listed, go)) = self.gate.lock().unwrap().take() {
+                listed.send(()).unwrap();
+                go.recv().unwrap();
+            }
+            locks
+        }
+
+        fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
+            self.inner.lock_file_fetch(p)
+        }
+
+        fn unlock_file(&self, p: &str) -> LockResult<()> {
+            self.inner.unlock_file(p)
+        }
+
+        fn unlock_id(&self, id: u32) -> LockResult<()> {
+            self.inner.unlock_id(id)
+        }
+    }
+
+    fn listings(store: &CachingLockStore<MonothreadLockStore<FakeGitBackend>>) -> usize {
+        store.inner().backend().calls().iter().filter(|call| **call == GitCall::ListLocks).count()
+    }
+
+    #[test]
+    fn lists_once_and_keeps_up_with_our_changes() {
+        let store = CachingLockStore::new(*MonothreadLockStore::with_backend(FakeGitBackend::new("alice", "main")), Duration::from_secs(60));
+        store.inner().backend().respond_locks(&[("a.uasset", "bob", 1)]).respond_lock("b.uasset", 2).respond(GitOutput::ok(""));
+        assert_eq!(store.get_locks().unwrap().len(), 1);
+        assert!(store.get_lock_file("a.uasset").unwrap().is_some());
+        assert!(store.get_lock_id(1).unwrap().is_some());
+        assert_eq!(listings(&store), 1);
+
+        store.lock_file_fetch("b.uasset").unwrap();
+        assert_eq!(store.get_lock_id(2).unwrap().unwrap().owner, "alice");
+        store.unlock_id(1).unwrap();
+        assert_eq!(store.get_locks().unwrap().iter().map(|lock| lock.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(listings(&store), 1);
+
+        store.inner().backend().respond_locks(&[]);
+        store.invalidate();
+        assert!(store.get_locks().unwrap().is_empty());
+        assert_eq!(listings(&store), 2);
+    }
+
+    #[test]
+    fn failures_and_expiry_refetch() {
+        let store = CachingLockStore::new(*MonothreadLockStore::with_backend(FakeGitBackend::default()), Duration::ZERO);
+        store.inner().backend().respond_locks(&[]).respond_locks(&[]);
+        store.get_locks().unwrap();
+        store.get_locks().unwrap();
+        assert_eq!(listings(&store), 2);
+
+        let store = CachingLockStore::new(*MonothreadLockStore::with_backend(FakeGitBackend::default()), Duration::from_secs(60));
+        store.inner().backend().respond_locks(&[]).respond(GitOutput::err("Lock exists")).respond_locks(&[("a.uasset", "bob", 1)]).respond_locks(&[("a.uasset", "bob", 1)]);
+        store.get_locks().unwrap();
+        // The inner store lists the locks itself to find out who beat us to it
+        assert!(store.lock_file_fetch("a.uasset").is_err());
+        assert_eq!(store.get_locks().unwrap().len(), 1);
+        assert_eq!(listings(&store), 3);
+    }
+
+    #[test]
+    fn changes_made_during_a_listing_are_kept() {
+        let (listed_tx, listed_rx) = mpsc::channel();
+        let (go_tx, go_rx) = mpsc::channel();
+        let store = CachingLockStore::new(SlowLister {
+            inner: InMemoryLockStore::new("alice"),
+            gate: Mutex::new(Some((listed_tx, go_rx))),
+        }, Duration::from_secs(60));
+        std::thread::scope(|scope| {
+            let listing = scope.spawn(|| store.get_raw_locks().unwrap());
+            listed_rx.recv().unwrap();
+            store.lock_file_fetch("a.uasset").unwrap();
+            go_tx.send(()).unwrap();
+            // The listing was taken before the lock, so it can't be what we remember
+            assert!(listing.join().unwrap().is_empty());
+        });
+        assert_eq!(store.get_raw_locks().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Mutex;
+
+use crate::git::BranchState;
+
+use super::git_backend::{GitBackend, GitOutput};
+
+// A single operation a store asked the backend to perform
+#[derive(Clone, Debug, PartialEq)]
+pub enum GitCall {
+    ListLocks,
+    Lock(String),
+    Unlock(String),
+    UnlockId(u32),
+}
+
+/* A scripted stand-in for git. Responses are handed out in the order they were scripted, regardless
+of which operation asks for them, and every operation is recorded so tests can assert on the exact
+sequence of calls a store made. Once the script runs dry every operation fails. */
+pub struct FakeGitBackend {
+    user: String,
+    branch: String,
+    responses: Mutex<VecDeque<GitOutput>>,
+    calls: Mutex<Vec<GitCall>>,
+    // Branches are Live unless told otherwise
+    branch_states: Mutex<HashMap<String, BranchState>>,
+}
+
+impl Default for FakeGitBackend {
+    fn default() -> Self {
+        FakeGitBackend::new("fake_user", "main")
+    }
+}
+
+impl FakeGitBackend {
+    pub fn new(user: &str, branch: &str) -> Self {
+        FakeGitBackend {
+            user: user.to_string(),
+            branch: branch.to_string(),
+            responses: Mutex::new(VecDeque::new()),
+            calls: Mutex::new(vec![]),
+            branch_states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Queue up the response for the next operation
+    pub fn respond(&self, out: GitOutput) -> &Self {
+        self.responses.lock().unwrap().push_back(out);
+        self
+    }
+
+    // Queue up a `git lfs locks --json` listing
+    pub fn respond_locks(&self, locks: &[(&str, &str, u32)]) -> &Self {
+        let entries: Vec<serde_json::Value> = locks.iter().map(|(file, owner, id)| serde_json::json!({
+            "id": id.to_string(),
+            "path": file,
+            "owner": { "name": owner },
+            "locked_at": "1970-01-01T00:00:00Z",
+        })).collect();
+        self.respond(GitOutput::ok(&serde_json::Value::Array(entries).to_string()))
+    }
+
+    // Queue up a successful `git lfs lock --json`
+    pub fn respond_lock(&self, file: &str, id: u32) -> &Self {
+        let json = serde_json::json!([{
+            "id": id.to_string(),
+            "path": file,
+            "owner": { "name": self.user },
+        }]);
+        self.respond(GitOutput::ok(&json.to_string()))
+    }
+
+    // Pretend a branch has been merged or deleted
+    pub fn set_branch_state(&self, branch: &str, state: BranchState) -> &Self {
+        self.branch_states.lock().unwrap().insert(branch.to_string(), state);
+        self
+    }
+
+    // Every operation performed so far, oldest first
+    pub fn calls(&self) -> Vec<GitCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    // Number of scripted responses that have not been consumed
+    pub fn pending(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+
+    fn next(&self, call: GitCall) -> io::Result<GitOutput> {
+        self.calls.lock().unwrap().push(call);
+        match self.responses.lock().unwrap().pop_front() {
+            Some(out) => Ok(out),
+            None => Ok(GitOutput::err("fake git: no scripted response")),
+        }
+    }
+}
+
+impl GitBackend for FakeGitBackend {
+    fn list_locks(&self) -> io::Result<GitOutput> {
+        self.next(GitCall::ListLocks)
+    }
+
+    fn lock(&self, p: &str) -> io::Result<GitOutput> {
+        self.next(GitCall::Lock(p.to_string()))
+    }
+
+    fn unlock(&self, p: &str) -> io::Result<GitOutput> {
+        self.next(GitCall::Unlock(p.to_string()))
+    }
+
+    fn unlock_id(&self, id: u32) -> io::Result<GitOutput> {
+        self.next(GitCall::UnlockId(id))
+    }
+
+    fn user(&self) -> String {
+        self.user.clone()
+    }
+
+    fn branch(&self) -> String {
+        self.branch.clone()
+    }
+
+    fn branch_state(&self, branch: &str, _target: &str) -> BranchState {
+        *self.branch_states.lock().unwrap().get(branch).unwrap_or(&BranchState::Live)
+    }
+
+    // A fake repo has no config, so every setting is at its default
+    fn config(&self, _key: &str) -> Option<String> {
+        None
+    }
+
+    fn dir(&self) -> String {
+        "/fake/repo".to_string()
+    }
+}
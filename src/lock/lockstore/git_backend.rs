@@ -0,0 +1,124 @@
+use std::io;
+
+use crate::git;
+
+// The result of a single git invocation, stripped down to what the lock stores care about
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GitOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl GitOutput {
+    pub fn ok(stdout: &str) -> Self {
+        GitOutput {
+            success: true,
+            stdout: stdout.to_string(),
+            stderr: "".to_string(),
+        }
+    }
+
+    pub fn err(stderr: &str) -> Self {
+        GitOutput {
+            success: false,
+            stdout: "".to_string(),
+            stderr: stderr.to_string(),
+        }
+    }
+}
+
+impl From<std::process::Output> for GitOutput {
+    fn from(out: std::process::Output) -> Self {
+        GitOutput {
+            success: out.status.success(),
+            stdout: String::from_utf8_lossy(&out.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+        }
+    }
+}
+
+// Everything a lock store needs from git. Swapping this out lets us run stores without an lfs server
+pub trait GitBackend {
+    // `git lfs locks --json`
+    fn list_locks(&self) -> io::Result<GitOutput>;
+    // `git lfs lock <p> --json`
+    fn lock(&self, p: &str) -> io::Result<GitOutput>;
+    // `git lfs unlock <p>`
+    fn unlock(&self, p: &str) -> io::Result<GitOutput>;
+    // `git lfs unlock --id <id>`
+    fn unlock_id(&self, id: u32) -> io::Result<GitOutput>;
+    // The lfs server's name for the current user
+    fn user(&self) -> String;
+    // The server just told us the current user's name, e.g. as the owner of a new lock
+    fn learn_user(&self, _user: &str) {}
+    // The currently checked out branch
+    fn branch(&self) -> String;
+    // Whether a branch has been merged into target or deleted
+    fn branch_state(&self, branch: &str, target: &str) -> git::BranchState;
+    // `git config --get <key>`
+    fn config(&self, key: &str) -> Option<String>;
+    // Where the checkout lives
+    fn dir(&self) -> String;
+}
+
+// Shells out to the git on the PATH, from the root of one repo
+pub struct SystemGit {
+    repo: git::Repo,
+}
+
+impl Default for SystemGit {
+    fn default() -> Self {
+        SystemGit::for_repo(git::Repo::current())
+    }
+}
+
+impl SystemGit {
+    pub fn for_repo(repo: git::Repo) -> Self {
+        SystemGit {
+            repo,
+        }
+    }
+}
+
+impl GitBackend for SystemGit {
+    fn list_locks(&self) -> io::Result<GitOutput> {
+        self.repo.run_lfs(&["locks", "--json"]).map(GitOutput::from)
+    }
+
+    fn lock(&self, p: &str) -> io::Result<GitOutput> {
+        self.repo.run_lfs(&["lock", p, "--json"]).map(GitOutput::from)
+    }
+
+    fn unlock(&self, p: &str) -> io::Result<GitOutput> {
+        self.repo.run_lfs(&["unlock", p]).map(GitOutput::from)
+    }
+
+    fn unlock_id(&self, id: u32) -> io::Result<GitOutput> {
+        self.repo.run_lfs(&["unlock", "--id", id.to_string().as_str()]).map(GitOutput::from)
+    }
+
+    fn user(&self) -> String {
+        self.repo.lfs_user()
+    }
+
+    fn learn_user(&self, user: &str) {
+        self.repo.learn_lfs_user(user)
+    }
+
+    fn branch(&self) -> String {
+        self.repo.branch()
+    }
+
+    fn branch_state(&self, branch: &str, target: &str) -> git::BranchState {
+        self.repo.branch_state(branch, target)
+    }
+
+    fn config(&self, key: &str) -> Option<String> {
+        self.repo.config(key)
+    }
+
+    fn dir(&self) -> String {
+        self.repo.root().to_string_lossy().to_string()
+    }
+}
@@ -0,0 +1,288 @@
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::git;
+use crate::lock::{LfsLock, RepoPath};
+use crate::lock::tag::dirtag;
+
+use super::{LockError, LockResult, LockStore};
+
+const LFS_MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+const PAGE_SIZE: u32 = 100;
+
+/* Talks to the Git LFS File Locking API directly instead of spawning git-lfs for every operation.
+See https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md */
+pub struct HttpLockStore {
+    // The lfs api root, e.g. https://host/org/repo.git/info/lfs
+    endpoint: String,
+    // Who we authenticate as, which the server records as the lock owner
+    user: Option<String>,
+    authorization: Option<String>,
+    agent: ureq::Agent,
+    // The checkout we're managing locks for, if we were made from one
+    repo: Option<git::Repo>,
+}
+
+// Map an http failure onto a LockError, using the lfs api's error body where there is one
+fn http_error(e: ureq::Error) -> LockError {
+    match e {
+        ureq::Error::Status(status, resp) => {
+            let body: Value = resp.into_json().unwrap_or(Value::Null);
+            let message = body["message"].as_str().unwrap_or("").to_string();
+            match status {
+                401 => LockError::NotAuthenticated,
+                404 => LockError::NotFound,
+                409 => LockError::AlreadyLocked {
+                    owner: body["lock"]["owner"]["name"].as_str().unwrap_or("unknown").to_string(),
+                },
+                _ => LockError::Other(format!("lock server returned {}: {}", status, message)),
+            }
+        },
+        ureq::Error::Transport(t) => LockError::NetworkFailure(t.to_string()),
+    }
+}
+
+fn parse_error(e: std::io::Error) -> LockError {
+    LockError::ParseError(e.to_string())
+}
+
+// Whether the repo opted in to talking to the lock api directly, with `git config lfs-wrangler.backend http`
+pub fn selected(repo: &git::Repo) -> bool {
+    repo.config("lfs-wrangler.backend").as_deref() == Some("http")
+}
+
+impl HttpLockStore {
+
+    pub fn new(endpoint: &str, credentials: Option<(String, String)>) -> Box<Self> {
+        let authorization = credentials.as_ref().map(|(user, pass)| {
+            let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+            format!("Basic {}", token)
+        });
+        Box::new(HttpLockStore {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            user: credentials.map(|(user, _)| user),
+            authorization,
+            agent: ureq::Agent::new(),
+            repo: None,
+        })
+    }
+
+    // Resolve the endpoint and credentials from a repo's config
+    pub fn from_repo(repo: &git::Repo) -> Option<Box<Self>> {
+        let endpoint = repo.lfs_endpoint()?;
+        let credentials = git::get_credentials(&endpoint);
+        let mut store = HttpLockStore::new(&endpoint, credentials);
+        store.repo = Some(repo.clone());
+        Some(store)
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let req = self.agent.request(method, &format!("{}/{}", self.endpoint, path))
+            .set("Accept", LFS_MEDIA_TYPE)
+            .set("Content-Type", LFS_MEDIA_TYPE);
+        match &self.authorization {
+            Some(auth) => req.set("Authorization", auth),
+            None => req,
+        }
+    }
+
+    // Locks matching the query, following cursors until the server runs out of pages
+    fn list(&self, query: &[(&str, String)]) -> LockResult<Vec<LfsLock>> {
+        let mut locks = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut req = self.request("GET", "locks").query("limit", &PAGE_SIZE.to_string());
+            for (key, value) in query {
+                req = req.query(key, value);
+            }
+            if let Some(c) = &cursor {
+                req = req.query("cursor", c);
+            }
+            let body: Value = req.call().map_err(http_error)?.into_json().map_err(parse_error)?;
+            let entries = body["locks"].as_array().ok_or(LockError::ParseError(format!("unexpected response: {}", body)))?;
+            for entry in entries {
+                locks.push(LfsLock::from_json(entry).map_err(LockError::ParseError)?);
+            }
+            match body["next_cursor"].as_str() {
+                Some(c) if !c.is_empty() => cursor = Some(c.to_string()),
+                _ => return Ok(locks),
+            }
+        }
+    }
+
+    // Split the server's locks into (ours, theirs) via POST /locks/verify
+    pub fn verify(&self) -> LockResult<(Vec<LfsLock>, Vec<LfsLock>)> {
+        let mut ours = vec![];
+        let mut theirs = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut payload = json!({ "limit": PAGE_SIZE });
+            if let Some(c) = &cursor {
+                payload["cursor"] = json!(c);
+            }
+            let body: Value = self.request("POST", "locks/verify").send_json(payload)
+                .map_err(http_error)?
+                .into_json()
+                .map_err(parse_error)?;
+            for (key, dest) in [("ours", &mut ours), ("theirs", &mut theirs)] {
+                for entry in body[key].as_array().unwrap_or(&vec![]) {
+                    dest.push(LfsLock::from_json(entry).map_err(LockError::ParseError)?);
+                }
+            }
+            match body["next_cursor"].as_str() {
+                Some(c) if !c.is_empty() => cursor = Some(c.to_string()),
+                _ => return Ok((ours, theirs)),
+            }
+        }
+    }
+}
+
+impl LockStore for HttpLockStore {
+
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
+        self.list(&[])
+    }
+
+    fn get_lock_file(&self, p: &str) -> LockResult<Option<LfsLock>> {
+        Ok(self.list(&[("path", RepoPath::new(p).to_string())])?.into_iter().next())
+    }
+
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
+        let resp = self.request("POST", "locks").send_json(json!({ "path": RepoPath::new(p).as_str() })).map_err(http_error)?;
+        let body: Value = resp.into_json().map_err(parse_error)?;
+        let lock = LfsLock::from_json(&body["lock"]).map_err(LockError::ParseError)?;
+        // Whoever owns a lock we just took is us, which settles any guess at our name
+        if let (None, Some(repo)) = (&self.user, &self.repo) {
+            repo.learn_lfs_user(&lock.owner);
+        }
+        Ok(lock)
+    }
+
+    fn unlock_file(&self, p: &str) -> LockResult<()> {
+        match self.get_lock_file(p)? {
+            None => Err(LockError::NotFound),
+            Some(lock) => self.unlock_id(lock.id),
+        }
+    }
+
+    fn unlock_id(&self, id: u32) -> LockResult<()> {
+        match self.request("POST", &format!("locks/{}/unlock", id)).send_json(json!({ "force": false })) {
+            Ok(_) => Ok(()),
+            // Servers refuse to release somebody else's lock without force
+            Err(ureq::Error::Status(403, _)) => {
+                let owner = self.list(&[("id", id.to_string())])?.into_iter().next().map(|lock| lock.owner);
+                Err(LockError::NotOwner { owner: owner.unwrap_or_else(|| "unknown".to_string()) })
+            },
+            Err(e) => Err(http_error(e)),
+        }
+    }
+
+    fn get_user(&self) -> String {
+        match (&self.user, &self.repo) {
+            (Some(user), _) => user.clone(),
+            (None, Some(repo)) => repo.lfs_user(),
+            (None, None) => git::get_lfs_user(),
+        }
+    }
+
+    fn get_branch(&self) -> String {
+        match &self.repo {
+            Some(repo) => repo.branch(),
+            None => git::get_branch(),
+        }
+    }
+
+    fn branch_state(&self, branch: &str, target: &str) -> git::BranchState {
+        match &self.repo {
+            Some(repo) => repo.branch_state(branch, target),
+            None => git::get_branch_state(branch, target),
+        }
+    }
+
+    fn get_config(&self, key: &str) -> Option<String> {
+        match &self.repo {
+            Some(repo) => repo.config(key),
+            None => git::get_config(key),
+        }
+    }
+
+    fn get_dir(&self) -> String {
+        match &self.repo {
+            Some(repo) => repo.root().to_string_lossy().to_string(),
+            None => dirtag::local_dir(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Serves canned bodies in order and records "METHOD url" for every request it sees
+    fn mock_server(responses: Vec<(u16, Value)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}/repo.git/info/lfs", server.server_addr().to_ip().unwrap());
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_by_server = seen.clone();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let req = match server.recv() {
+                    Ok(req) => req,
+                    Err(_) => return,
+                };
+                seen_by_server.lock().unwrap().push(format!("{} {}", req.method(), req.url()));
+                let resp = tiny_http::Response::from_string(body.to_string()).with_status_code(status);
+                let _ = req.respond(resp);
+            }
+        });
+        (addr, seen)
+    }
+
+    fn lock_json(id: u32, path: &str, owner: &str) -> Value {
+        json!({ "id": id.to_string(), "path": path, "owner": { "name": owner }, "locked_at": "2024-01-01T00:00:00Z" })
+    }
+
+    #[test]
+    fn get_raw_locks_follows_cursors() {
+        let (addr, seen) = mock_server(vec![
+            (200, json!({ "locks": [lock_json(1, "a.uasset", "alice")], "next_cursor": "2" })),
+            (200, json!({ "locks": [lock_json(2, "b c.uasset", "bob")] })),
+        ]);
+        let store = HttpLockStore::new(&addr, None);
+        let locks = store.get_raw_locks().unwrap();
+        assert_eq!(locks.len(), 2);
+        assert_eq!(locks[1].file, "b c.uasset");
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0], "GET /repo.git/info/lfs/locks?limit=100");
+        assert_eq!(seen[1], "GET /repo.git/info/lfs/locks?limit=100&cursor=2");
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let (addr, seen) = mock_server(vec![
+            (201, json!({ "lock": lock_json(7, "a.uasset", "alice") })),
+            (409, json!({ "lock": lock_json(7, "a.uasset", "alice"), "message": "already created lock" })),
+            (200, json!({ "lock": lock_json(7, "a.uasset", "alice") })),
+        ]);
+        let store = HttpLockStore::new(&addr, Some(("alice".into(), "secret".into())));
+        let lock = store.lock_file_fetch("a.uasset").unwrap();
+        assert_eq!(lock.id, 7);
+        assert_eq!(store.lock_file_fetch("a.uasset").unwrap_err(), LockError::AlreadyLocked { owner: "alice".into() });
+        assert!(store.unlock_id(7).is_ok());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[2], "POST /repo.git/info/lfs/locks/7/unlock");
+    }
+
+    #[test]
+    fn verify_splits_ours_and_theirs() {
+        let (addr, _) = mock_server(vec![
+            (200, json!({ "ours": [lock_json(1, "a", "alice")], "theirs": [lock_json(2, "b", "bob")] })),
+        ]);
+        let store = HttpLockStore::new(&addr, None);
+        let (ours, theirs) = store.verify().unwrap();
+        assert_eq!(ours[0].owner, "alice");
+        assert_eq!(theirs[0].owner, "bob");
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::git::BranchState;
+
+use crate::lock::LfsLock;
+
+use super::{LockError, LockResult, LockStore};
+
+struct ServerLock {
+    id: u32,
+    file: String,
+    owner: String,
+}
+
+// The simulated lfs server every InMemoryLockStore handle shares
+#[derive(Default)]
+struct ServerState {
+    last_id: u32,
+    locks: Vec<ServerLock>,
+}
+
+/* A LockStore that simulates an lfs lock server in memory. Handles made with `as_user` share the
+same server, so several users can contend for locks without any network access */
+pub struct InMemoryLockStore {
+    user: String,
+    branch: String,
+    host: String,
+    records: bool,
+    record_os_user: bool,
+    merge_target: Option<String>,
+    // Branches are Live unless told otherwise
+    branch_states: HashMap<String, BranchState>,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl InMemoryLockStore {
+
+    pub fn new(user: &str) -> Box<Self> {
+        Box::new(InMemoryLockStore {
+            user: user.to_string(),
+            branch: "main".to_string(),
+            host: "localhost".to_string(),
+            records: false,
+            record_os_user: false,
+            merge_target: None,
+            branch_states: HashMap::new(),
+            state: Arc::new(Mutex::new(ServerState::default())),
+        })
+    }
+
+    // A handle to the same server acting as a different user
+    pub fn as_user(&self, user: &str) -> Box<Self> {
+        Box::new(InMemoryLockStore {
+            user: user.to_string(),
+            branch: self.branch.clone(),
+            host: self.host.clone(),
+            records: self.records,
+            record_os_user: self.record_os_user,
+            merge_target: self.merge_target.clone(),
+            branch_states: self.branch_states.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    // Change the branch new locks get associated with
+    pub fn checkout(&mut self, branch: &str) {
+        self.branch = branch.to_string();
+    }
+
+    // Pretend to be running on a different machine
+    pub fn set_host(&mut self, host: &str) {
+        self.host = host.to_string();
+    }
+
+    // Opt in to releasing locks whose branch has been merged into target
+    pub fn set_merge_target(&mut self, target: Option<&str>) {
+        self.merge_target = target.map(|t| t.to_string());
+    }
+
+    // Pretend a branch has been merged or deleted
+    pub fn set_branch_state(&mut self, branch: &str, state: BranchState) {
+        self.branch_states.insert(branch.to_string(), state);
+    }
+
+    // Fold tags into one record lock instead of a lock per tag
+    pub fn set_records_mode(&mut self, records: bool) {
+        self.records = records;
+    }
+
+    // Have new locks record the local OS user as well as the host
+    pub fn set_records_os_user(&mut self, record: bool) {
+        self.record_os_user = record;
+    }
+}
+
+impl LockStore for InMemoryLockStore {
+
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.locks.iter().map(|lock| LfsLock {
+            file: lock.file.as_str().into(),
+            owner: lock.owner.clone(),
+            id: lock.id,
+            ..Default::default()
+        }).collect())
+    }
+
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(existing) = state.locks.iter().find(|lock| lock.file == *p) {
+            return Err(LockError::AlreadyLocked { owner: existing.owner.clone() });
+        }
+        state.last_id += 1;
+        let id = state.last_id;
+        state.locks.push(ServerLock {
+            id,
+            file: p.into(),
+            owner: self.user.clone(),
+        });
+        Ok(LfsLock {
+            file: p.into(),
+            owner: self.user.clone(),
+            id,
+            ..Default::default()
+        })
+    }
+
+    fn unlock_file(&self, p: &str) -> LockResult<()> {
+        let id = match self.state.lock().unwrap().locks.iter().find(|lock| lock.file == *p) {
+            None => return Err(LockError::NotFound),
+            Some(lock) => lock.id,
+        };
+        self.unlock_id(id)
+    }
+
+    fn unlock_id(&self, id: u32) -> LockResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.locks.iter().position(|lock| lock.id == id).ok_or(LockError::NotFound)?;
+        if state.locks[index].owner != self.user {
+            return Err(LockError::NotOwner { owner: state.locks[index].owner.clone() });
+        }
+        state.locks.remove(index);
+        Ok(())
+    }
+
+    fn get_user(&self) -> String {
+        self.user.clone()
+    }
+
+    fn get_branch(&self) -> String {
+        self.branch.clone()
+    }
+
+    fn get_host(&self) -> String {
+        self.host.clone()
+    }
+
+    fn records_mode(&self) -> bool {
+        self.records
+    }
+
+    fn records_os_user(&self) -> bool {
+        self.record_os_user
+    }
+
+    fn branch_state(&self, branch: &str, _target: &str) -> BranchState {
+        *self.branch_states.get(branch).unwrap_or(&BranchState::Live)
+    }
+
+    fn merge_target(&self) -> Option<String> {
+        self.merge_target.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::lockstore::stale_branch_locks;
+    use crate::lock::tag::*;
+
+    #[test]
+    fn ids_are_monotonic_and_paths_exclusive() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        assert_eq!(alice.lock_file_fetch("a").unwrap().id, 1);
+        assert_eq!(bob.lock_file_fetch("a").unwrap_err(), LockError::AlreadyLocked { owner: "alice".into() });
+        assert!(alice.unlock_file("a").is_ok());
+        assert_eq!(bob.lock_file_fetch("a").unwrap().id, 2);
+    }
+
+    #[test]
+    fn only_owner_can_unlock() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let lock = alice.lock_file_fetch("a").unwrap();
+        assert_eq!(bob.unlock_id(lock.id).unwrap_err(), LockError::NotOwner { owner: "alice".into() });
+        assert!(bob.unlock_file(&lock.file).is_err());
+        assert!(alice.unlock_id(lock.id).is_ok());
+        assert_eq!(alice.unlock_id(lock.id).unwrap_err(), LockError::NotFound);
+    }
+
+    #[test]
+    fn update_hands_off_to_queue() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        queuetag::QueueTag::new(&lock, bob.get_user()).save(&*bob);
+        assert_eq!(alice.get_locks().unwrap()[0].queue, vec!["bob".to_string()]);
+
+        assert!(alice.unlock_file(&file).is_ok());
+        alice.update();
+        bob.update();
+
+        let locks = bob.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner, "bob");
+        assert!(locks[0].queue.is_empty());
+        assert_eq!(bob.get_raw_locks().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn records_mode_keeps_one_companion_lock() {
+        let mut alice = InMemoryLockStore::new("alice");
+        alice.set_records_mode(true);
+        let bob = alice.as_user("bob");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        assert_eq!(alice.get_raw_locks().unwrap().len(), 2);
+        queuetag::QueueTag::new(&lock, bob.get_user()).save(&*bob);
+        assert_eq!(alice.get_raw_locks().unwrap().len(), 3);
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(locks[0].branch, Some("main".into()));
+        assert_eq!(locks[0].queue, vec!["bob".to_string()]);
+
+        assert!(alice.unlock_file(&file).is_ok());
+        alice.update();
+        bob.update();
+
+        let locks = bob.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner, "bob");
+        assert!(locks[0].queue.is_empty());
+        assert!(locks[0].dir.is_some());
+        assert_eq!(bob.get_raw_locks().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn update_releases_locks_on_finished_branches() {
+        let mut alice = InMemoryLockStore::new("alice");
+        alice.checkout("feature");
+        let merged = alice.lock_real_file("a.uasset").unwrap();
+        alice.checkout("gone");
+        alice.lock_real_file("b.uasset").unwrap();
+        alice.checkout("main");
+        let live = alice.lock_real_file("c.uasset").unwrap();
+        alice.set_branch_state("feature", BranchState::Merged);
+        alice.set_branch_state("gone", BranchState::Deleted);
+
+        let stale = stale_branch_locks(&*alice, "main").unwrap();
+        assert_eq!(stale.len(), 2);
+        assert!(stale.iter().any(|(lock, state)| lock.id == merged.id && *state == BranchState::Merged));
+        // Nothing happens until the repo opts in
+        alice.update();
+        assert_eq!(alice.get_locks().unwrap().len(), 3);
+
+        alice.set_merge_target(Some("main"));
+        alice.update();
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].id, live.id);
+        assert_eq!(alice.get_raw_locks().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn update_leaves_locks_from_other_machines_alone() {
+        let mut laptop = InMemoryLockStore::new("alice");
+        laptop.set_host("laptop");
+        laptop.checkout("unpushed");
+        laptop.lock_real_file("a.uasset").unwrap();
+        // The desktop has never heard of the laptop's branch
+        let mut desktop = laptop.as_user("alice");
+        desktop.set_host("desktop");
+        desktop.checkout("main");
+        desktop.set_branch_state("unpushed", BranchState::Deleted);
+        desktop.set_merge_target(Some("main"));
+
+        assert!(stale_branch_locks(&*desktop, "main").unwrap().is_empty());
+        desktop.update();
+        assert_eq!(laptop.get_locks().unwrap().len(), 1);
+    }
+}
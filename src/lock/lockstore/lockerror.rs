@@ -0,0 +1,93 @@
+use core::fmt;
+use std::io;
+
+// Everything that can go wrong talking to an lfs lock server
+#[derive(Clone, Debug, PartialEq)]
+pub enum LockError {
+    // Somebody already holds the lock
+    AlreadyLocked { owner: String },
+    // The lock belongs to somebody else, so we can't release it
+    NotOwner { owner: String },
+    // The file was handed off to somebody else, who gets first claim on it
+    HandedOff { recipient: String },
+    // There is no such lock
+    NotFound,
+    // The server couldn't be reached
+    NetworkFailure(String),
+    // git or git-lfs isn't installed
+    GitMissing,
+    // The server rejected our credentials
+    NotAuthenticated,
+    // The server answered with something we couldn't make sense of
+    ParseError(String),
+    // Anything else, with whatever explanation we were given
+    Other(String),
+}
+
+pub type LockResult<T> = Result<T, LockError>;
+
+impl LockError {
+    /* Classify a failed git-lfs invocation from its stderr. `owner` is consulted to fill in who holds
+    the lock when git-lfs doesn't tell us */
+    pub fn from_git_stderr(stderr: &str, owner: impl FnOnce() -> Option<String>) -> LockError {
+        let lower = stderr.to_lowercase();
+        if lower.contains("'lfs' is not a git command") {
+            LockError::GitMissing
+        } else if lower.contains("already created") || lower.contains("already locked") || lower.contains("lock exists") {
+            LockError::AlreadyLocked { owner: owner().unwrap_or_else(|| "unknown".to_string()) }
+        } else if let Some(idx) = lower.find("owned by") {
+            let rest = stderr[idx + "owned by".len()..].trim();
+            let name = rest.split_whitespace().next().unwrap_or("unknown").trim_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-');
+            LockError::NotOwner { owner: name.to_string() }
+        } else if lower.contains("authorization") || lower.contains("authentication") || lower.contains("401") || lower.contains("credentials") {
+            LockError::NotAuthenticated
+        } else if lower.contains("not found") || lower.contains("no matching lock") || lower.contains("unable to get lock id") {
+            LockError::NotFound
+        } else if lower.contains("connection") || lower.contains("dial tcp") || lower.contains("could not resolve") || lower.contains("timeout") {
+            LockError::NetworkFailure(stderr.trim().to_string())
+        } else {
+            LockError::Other(stderr.trim().to_string())
+        }
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => LockError::GitMissing,
+            _ => LockError::Other(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockError::AlreadyLocked { owner } => write!(f, "already locked by {}", owner),
+            LockError::NotOwner { owner } => write!(f, "lock is owned by {}", owner),
+            LockError::HandedOff { recipient } => write!(f, "being handed off to {}, who gets it first", recipient),
+            LockError::NotFound => write!(f, "lock does not exist"),
+            LockError::NetworkFailure(e) => write!(f, "could not reach the lock server: {}", e),
+            LockError::GitMissing => write!(f, "git or git-lfs is not installed"),
+            LockError::NotAuthenticated => write!(f, "not authenticated with the lock server"),
+            LockError::ParseError(e) => write!(f, "could not understand the lock server: {}", e),
+            LockError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_git_lfs_errors() {
+        assert_eq!(LockError::from_git_stderr("Locking a.txt failed: lock already created", || Some("bob".into())), LockError::AlreadyLocked { owner: "bob".into() });
+        assert_eq!(LockError::from_git_stderr("Unable to unlock: lock is owned by bob", || None), LockError::NotOwner { owner: "bob".into() });
+        assert_eq!(LockError::from_git_stderr("git: 'lfs' is not a git command. See 'git --help'.", || None), LockError::GitMissing);
+        assert_eq!(LockError::from_git_stderr("batch response: Authorization error: https://host", || None), LockError::NotAuthenticated);
+        assert!(matches!(LockError::from_git_stderr("dial tcp 127.0.0.1:1: connect: connection refused", || None), LockError::NetworkFailure(_)));
+    }
+}
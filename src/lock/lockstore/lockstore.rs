@@ -1,27 +1,172 @@
+use core::fmt;
+
+use crate::git::{self, BranchState};
 use crate::lock::LfsLock;
 use crate::lock::tag::*;
 
-fn normalize_path(p: &String) -> String {
-    let s = p.replace("\\", "/");
-    match s.strip_prefix("./") {
-        None => s,
-        Some(stripped) => stripped.to_string(),
+use super::{LockError, LockResult};
+
+// A lock update() let go of, and why
+#[derive(Clone, Debug)]
+pub enum Released {
+    Expired(LfsLock),
+    FinishedBranch(LfsLock, BranchState),
+}
+
+impl fmt::Display for Released {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Released::Expired(lock) => write!(f, "Released {}: lock expired", lock.file),
+            Released::FinishedBranch(lock, state) => write!(f, "Released {}: branch {} was {}", lock.file, lock.branch.clone().unwrap_or_default(), state),
+        }
+    }
+}
+
+// What update() got done, for whoever ran it to pass on
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    pub released: Vec<Released>,
+    // What we were doing when it went wrong, and what went wrong
+    pub errors: Vec<(String, LockError)>,
+}
+
+// Give up every lock the current user holds that has run past its ExpiryTag deadline. Hands back each lock we tried and how it went
+pub fn release_expired_locks(store: &dyn LockStore) -> LockResult<Vec<(LfsLock, LockResult<()>)>> {
+    let user = store.get_user();
+    let now = chrono::Utc::now();
+    let expired = store.get_locks()?.into_iter().filter(|lock| lock.owner == user && lock.expires_at.is_some_and(|deadline| deadline <= now));
+    Ok(expired.map(|lock| {
+        let result = store.unlock_id(lock.id);
+        (lock, result)
+    }).collect())
+}
+
+/* Whether a lock was taken from this checkout. Branches only exist where they were made until they're
+pushed, so only here can we tell what has become of a lock's branch */
+fn taken_here(store: &dyn LockStore, lock: &LfsLock) -> bool {
+    lock.host.as_ref() == Some(&store.get_host()) && lock.dir.as_ref().is_none_or(|dir| *dir == store.get_dir())
+}
+
+// The current user's locks, taken from this checkout, whose BranchTag branch has been merged into target or deleted
+pub fn stale_branch_locks(store: &dyn LockStore, target: &str) -> LockResult<Vec<(LfsLock, BranchState)>> {
+    let user = store.get_user();
+    let mut stale = vec![];
+    for lock in store.get_locks()?.into_iter().filter(|lock| lock.owner == user && taken_here(store, lock)) {
+        let state = match &lock.branch {
+            Some(branch) => store.branch_state(branch, target),
+            None => BranchState::Live,
+        };
+        if state != BranchState::Live {
+            stale.push((lock, state));
+        }
+    }
+    Ok(stale)
+}
+
+// Give up locks whose branch is finished with, for repos that opted in with a merge target
+pub fn release_stale_branch_locks(store: &dyn LockStore) -> LockResult<Vec<(LfsLock, BranchState, LockResult<()>)>> {
+    let target = match store.merge_target() {
+        Some(target) => target,
+        None => return Ok(vec![]),
+    };
+    Ok(stale_branch_locks(store, &target)?.into_iter().map(|(lock, state)| {
+        let result = store.unlock_id(lock.id);
+        (lock, state, result)
+    }).collect())
+}
+
+/* Release every tag the current user holds whose target lock has gone away. Hands back the tags that
+wouldn't go, which will get another try next time */
+pub fn cleanup_orphan_tags(store: &dyn LockStore) -> LockResult<Vec<(String, LockError)>> {
+    let user = store.get_user();
+    let mut last_orphans = vec![];
+    let mut failures = vec![];
+    loop {
+        let locks = store.get_raw_locks()?;
+        let mut orphan_tags = vec![];
+        for lock in &locks {
+            if lock.owner != user {
+                continue;
+            }
+            if let Some(tag) = tag::get_tag(lock) {
+                if !locks.iter().any(|lock| lock.id == tag.get_target_id()) {
+                    orphan_tags.push(tag);
+                }
+            }
+        }
+        // Tags that refused to go last time (e.g. a queue still waiting on the server) will refuse again
+        let orphans: Vec<String> = orphan_tags.iter().map(|tag| tag.get_lock_string()).collect();
+        if orphans.is_empty() || orphans == last_orphans {
+            return Ok(failures);
+        }
+        failures.clear();
+        for tag in orphan_tags {
+            if let Err(e) = tag.cleanup(store) {
+                failures.push((format!("Cleaning up after lock {}", tag.get_target_id()), e));
+            }
+        }
+        last_orphans = orphans;
+    }
+}
+
+// Run the regular upkeep over a store, gathering up what happened
+pub fn update(store: &dyn LockStore) -> UpdateReport {
+    let mut report = UpdateReport::default();
+    match release_expired_locks(store) {
+        Ok(tried) => {
+            for (lock, result) in tried {
+                match result {
+                    Ok(_) => report.released.push(Released::Expired(lock)),
+                    Err(e) => report.errors.push((format!("Releasing expired lock on {}", lock.file), e)),
+                }
+            }
+        },
+        Err(e) => report.errors.push(("Releasing expired locks".to_string(), e)),
+    }
+    match release_stale_branch_locks(store) {
+        Ok(tried) => {
+            for (lock, state, result) in tried {
+                match result {
+                    Ok(_) => report.released.push(Released::FinishedBranch(lock, state)),
+                    Err(e) => report.errors.push((format!("Releasing {}", lock.file), e)),
+                }
+            }
+        },
+        Err(e) => report.errors.push(("Releasing locks on finished branches".to_string(), e)),
+    }
+    match cleanup_orphan_tags(store) {
+        Ok(failures) => report.errors.extend(failures),
+        Err(e) => report.errors.push(("Cleaning up orphaned tags".to_string(), e)),
+    }
+    report
+}
+
+// Lets the trait's own methods hand the store on to helpers that take any store
+pub trait AsLockStore {
+    fn as_lock_store(&self) -> &dyn LockStore;
+}
+
+impl<S: LockStore> AsLockStore for S {
+    fn as_lock_store(&self) -> &dyn LockStore {
+        self
     }
 }
 
 // A trait for extracting LfsLocks from a repo
-pub trait LockStore {
+pub trait LockStore: AsLockStore {
 
     // Straight pipe from git to untagged locks
-    fn get_raw_locks(&self) -> Vec<LfsLock>;
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>>;
 
     // Pull down fully tagged and qualified locks
-    fn get_locks(&self) -> Vec<LfsLock> {
-        let locks = self.get_raw_locks();
+    fn get_locks(&self) -> LockResult<Vec<LfsLock>> {
+        let locks = self.get_raw_locks()?;
         let mut real_locks = vec![];
         let mut tags = vec![];
         for lock in locks {
             match tag::get_tag(&lock) {
+                // A tag kind we don't know about, e.g. from a newer client. Still not a real file
+                None if encoding::is_tag_path(&lock.file) => (),
                 None => real_locks.push(lock),
                 Some(tag) => tags.push(tag),
             }
@@ -32,59 +177,135 @@ pub trait LockStore {
                 Some(l) => tag.apply(l),
             }
         }
-        real_locks
+        Ok(real_locks)
     }
 
     // Pull down fully tagged and qualified lock
-    fn get_lock_file(&self, p: &String) -> Option<LfsLock> {
-        self.get_raw_locks().into_iter().find(|lock| normalize_path(&lock.file) == normalize_path(p))
+    fn get_lock_file(&self, p: &str) -> LockResult<Option<LfsLock>> {
+        Ok(self.get_raw_locks()?.into_iter().find(|lock| lock.file == *p))
     }
 
     // Pull down fully tagged and qualified lock
-    fn get_lock_id(&self, id: u32) -> Option<LfsLock> {
-        self.get_locks().into_iter().filter(|lock| lock.id == id).last()
+    fn get_lock_id(&self, id: u32) -> LockResult<Option<LfsLock>> {
+        Ok(self.get_locks()?.into_iter().find(|lock| lock.id == id))
+    }
+
+    /* Find pending actions and execute them. e.g. releasing expired locks, cleaning up orphaned tags or
+    deleting locks when the owning branch no longer exists */
+    fn update(&self) -> UpdateReport {
+        update(self.as_lock_store())
     }
 
-    /* Find pending actions and execute them. e.g. cleaning up orphaned tags or deleting locks when
-    the owning branch no longer exists */
-    fn update(&self);
+    // Forget anything remembered about the server's locks, so the next look at them is fresh
+    fn invalidate(&self) {}
 
     // Lock a file
-    fn lock_file(&self, p: &String) -> bool  {
-        self.lock_file_fetch(p).is_some()
+    fn lock_file(&self, p: &str) -> LockResult<()> {
+        self.lock_file_fetch(p).map(|_| ())
     }
 
-    fn lock_file_fast(&self, p: &String) {
-        self.lock_file_fetch(p);
+    fn lock_file_fast(&self, p: &str) {
+        let _ = self.lock_file_fetch(p);
     }
 
-    // locks a file, then returns the newly created lock or None if it already exists
-    fn lock_file_fetch(&self, p: &String) -> Option<LfsLock>;
+    // locks a file, then returns the newly created lock
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock>;
 
     // lock a real file, not an arbitrary path
-    fn lock_real_file(&self, p: &String) -> Option<LfsLock> {
-        match self.lock_file_fetch(p) {
-            None => None,
-            Some(lock) => {
-                let bt = branchtag::for_lock(&lock);
-                let dt = dirtag::for_lock(&lock);
-                self.lock_file_fast(&bt.get_lock_string());
-                self.lock_file_fast(&dt.get_lock_string());
-                Some(lock)
+    fn lock_real_file(&self, p: &str) -> LockResult<LfsLock> {
+        self.lock_real_file_with(p, &self.get_raw_locks()?)
+    }
+
+    /* Same again, checking for handoffs against a raw listing the caller already has. Locking a batch of
+    files then only lists the locks once */
+    fn lock_real_file_with(&self, p: &str, raw_locks: &[LfsLock]) -> LockResult<LfsLock> {
+        // Somebody handed this file to a colleague, who gets the first go at it
+        if let Some(handoff) = handofftag::pending_for(raw_locks, p) {
+            if *handoff.recipient() != self.get_user() {
+                return Err(LockError::HandedOff { recipient: handoff.recipient().clone() });
             }
         }
+        let lock = self.lock_file_fetch(p)?;
+        let os_user = match self.records_os_user() {
+            true => hosttag::local_os_user(),
+            false => None,
+        };
+        let tags = [
+            branchtag::BranchTag::new(lock.id, self.get_branch()).get_lock_string(),
+            dirtag::DirTag::new(lock.id, self.get_dir()).get_lock_string(),
+            hosttag::HostTag::new(lock.id, self.get_host(), os_user).get_lock_string(),
+        ];
+        match self.records_mode() {
+            true => {
+                let _ = recordtag::add(self, lock.id, &tags);
+            },
+            false => {
+                for tag in &tags {
+                    self.lock_file_fast(tag);
+                }
+            },
+        }
+        Ok(lock)
     }
 
-    fn unlock_file(&self, p: &String) -> bool;
+    fn unlock_file(&self, p: &str) -> LockResult<()>;
 
-    fn unlock_file_fast(&self, p: &String) {
-        self.unlock_file(p);
+    fn unlock_file_fast(&self, p: &str) {
+        let _ = self.unlock_file(p);
     }
 
-    fn unlock_id(&self, id: u32) -> bool;
+    fn unlock_id(&self, id: u32) -> LockResult<()>;
 
     fn unlock_id_fast(&self, id: u32) {
-        self.unlock_id(id);
+        let _ = self.unlock_id(id);
+    }
+
+    // The lfs server's name for whoever is driving this store
+    fn get_user(&self) -> String {
+        git::get_lfs_user()
+    }
+
+    // The branch new locks should be associated with
+    fn get_branch(&self) -> String {
+        git::get_branch()
     }
 
-}
\ No newline at end of file
+    // The machine new locks should be associated with
+    fn get_host(&self) -> String {
+        hosttag::local_host()
+    }
+
+    // The checkout new locks should be associated with
+    fn get_dir(&self) -> String {
+        dirtag::local_dir()
+    }
+
+    // A setting from the repo's git config
+    fn get_config(&self, key: &str) -> Option<String> {
+        git::get_config(key)
+    }
+
+    // What has become of a branch locks were taken on
+    fn branch_state(&self, branch: &str, target: &str) -> BranchState {
+        git::get_branch_state(branch, target)
+    }
+
+    /* The branch that, once a lock's branch is merged into it, lets update() release the lock. None
+    unless the repo opted in with `git config lfs-wrangler.releaseMergedInto main` */
+    fn merge_target(&self) -> Option<String> {
+        self.get_config("lfs-wrangler.releaseMergedInto")
+    }
+
+    /* Whether tags are folded into one record lock per lock and user rather than a lock apiece.
+    Opt in per repo with `git config lfs-wrangler.records true` */
+    fn records_mode(&self) -> bool {
+        self.get_config("lfs-wrangler.records").is_some_and(|v| v == "true")
+    }
+
+    /* Whether new locks also record who is logged in to the machine, for everyone on the server to see.
+    Opt in per repo with `git config lfs-wrangler.recordOsUser true` */
+    fn records_os_user(&self) -> bool {
+        self.get_config("lfs-wrangler.recordOsUser").is_some_and(|v| v == "true")
+    }
+
+}
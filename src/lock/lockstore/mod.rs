@@ -1,5 +1,14 @@
+#[allow(clippy::module_inception)]
 pub mod lockstore;
+pub mod lockerror;
 pub mod monothread_lockstore;
 pub mod multithreaded_lockstore;
+pub mod inmemory_lockstore;
+pub mod http_lockstore;
+pub mod caching_lockstore;
+pub mod git_backend;
+pub mod fake_git_backend;
 
-pub use lockstore::LockStore;
\ No newline at end of file
+pub use lockstore::{LockStore, Released, UpdateReport};
+pub use lockerror::{LockError, LockResult};
+pub use git_backend::GitBackend;
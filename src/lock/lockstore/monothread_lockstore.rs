@@ -1,107 +1,178 @@
-use crate::git;
 use crate::lock::LfsLock;
-use crate::lock::tag::*;
 
-use std::process::Command;
-use std::os::windows::process::CommandExt;
+use super::git_backend::{GitBackend, SystemGit};
+use crate::git::{self, BranchState};
+use super::{LockError, LockResult, LockStore};
 
-use super::LockStore;
+#[derive(Default)]
+pub struct MonothreadLockStore<B: GitBackend = SystemGit> {
+    backend: B,
+}
 
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+impl MonothreadLockStore {
 
-pub struct MonothreadLockStore {
-}
+    pub fn new() -> Box<Self> {
+        Box::new(MonothreadLockStore::default())
+    }
 
-impl Default for MonothreadLockStore {
-    fn default() -> Self {
-        MonothreadLockStore {
-        }
+    // A store for the locks of a particular repo, rather than the one we were run from
+    pub fn for_repo(repo: git::Repo) -> Box<Self> {
+        MonothreadLockStore::with_backend(SystemGit::for_repo(repo))
     }
 }
 
-impl MonothreadLockStore {
+impl<B: GitBackend> MonothreadLockStore<B> {
 
-    pub fn new() -> Box<Self> {
-        Box::new(MonothreadLockStore::default())
+    // Build a store that talks to git through the given backend
+    pub fn with_backend(backend: B) -> Box<Self> {
+        Box::new(MonothreadLockStore {
+            backend,
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
     }
 }
 
-impl LockStore for MonothreadLockStore {
+impl<B: GitBackend> LockStore for MonothreadLockStore<B> {
 
     // Fetches raw locks
-    fn get_raw_locks(&self) -> Vec<LfsLock> {
-        let out = Command::new("cmd").args(["/C", "git lfs locks"]).creation_flags(CREATE_NO_WINDOW).output().expect("Failed to execute process");
-        let out = String::from_utf8_lossy(&out.stdout).to_string();
-        let lines: Vec<&str> = out.split("\n").filter(|&s| !s.is_empty()).collect();
-        let locks: Vec<LfsLock> = lines.iter().map(|&l| LfsLock::from_line(l.to_string()).unwrap()).collect();
-        locks
-    }
-
-    fn lock_file_fetch(&self, p: &String) -> Option<LfsLock> {
-        let lock = ["git lfs lock", p, "--json"].join(" ");
-        let cmd = Command::new("cmd").args(["/C", &lock]).creation_flags(CREATE_NO_WINDOW).output();
-        match cmd {
-            Err(e) => {
-                println!("Error: {}", e.to_string());
-                None
-            },
-            Ok(r) => {
-                if !r.status.success() {
-                    return None;
-                }
-                let json_str = std::str::from_utf8(&r.stdout).expect("Failed to get stdout");
-                let json: serde_json::Value = serde_json::from_str(json_str).expect("Failed to parse json");
-                let id = str::parse::<u32>(json[0]["id"].as_str().unwrap()).expect("Failed to parse value");
-                Some(LfsLock{
-                    file: p.clone(),
-                    owner: git::get_lfs_user(),
-                    id: id as u32,
-                    branch: None,
-                    dir: None,
-                    queue: vec![],
-                })
-            }
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
+        let out = self.backend.list_locks()?;
+        if !out.success {
+            return Err(LockError::from_git_stderr(&out.stderr, || None));
+        }
+        if out.stdout.trim().is_empty() {
+            return Ok(vec![]);
         }
+        let entries = match serde_json::from_str::<serde_json::Value>(&out.stdout) {
+            Ok(serde_json::Value::Array(entries)) => entries,
+            _ => return Err(LockError::ParseError(format!("unexpected output from git lfs locks: {}", out.stdout))),
+        };
+        entries.iter().map(|entry| LfsLock::from_json(entry).map_err(LockError::ParseError)).collect()
     }
 
-    fn unlock_file(&self, p: &String) -> bool {
-        let out = Command::new("cmd").args(["/C".into(), ["git lfs unlock ", p.as_str()].join("")]).creation_flags(CREATE_NO_WINDOW).output();
-        match out {
-            Err(_) => false,
-            Ok(r) => r.status.success(),
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
+        let r = self.backend.lock(p)?;
+        if !r.success {
+            return Err(LockError::from_git_stderr(&r.stderr, || {
+                self.get_lock_file(p).ok().flatten().map(|lock| lock.owner)
+            }));
         }
+        let json: serde_json::Value = serde_json::from_str(&r.stdout).map_err(|e| LockError::ParseError(e.to_string()))?;
+        // Depending on the git-lfs version we get either the lock or a list of locks
+        let entry = match &json {
+            serde_json::Value::Array(entries) => entries.first().unwrap_or(&serde_json::Value::Null),
+            _ => &json,
+        };
+        let mut lock = LfsLock::from_json(entry).map_err(LockError::ParseError)?;
+        lock.file = p.into();
+        // Whoever owns a lock we just took is us, which settles any guess at our name
+        self.backend.learn_user(&lock.owner);
+        Ok(lock)
     }
 
-    fn update(&self) {
-        let user = git::get_lfs_user();
-        let locks = self.get_raw_locks();
-        let mut orphan_tags = vec![];
-        for lock in &locks {
-            if lock.owner != user {
-                continue;
-            }
-            match tag::get_tag(&lock) {
-                None => (),
-                Some(tag) => {
-                    if locks.iter().find(|lock| lock.id == tag.get_target_id()).is_none() {
-                        orphan_tags.push(tag);
-                    }
-                },
-            }
-        }
-        if !orphan_tags.is_empty() {
-            for tag in orphan_tags {
-                tag.cleanup(self);
-            }
-            self.update();
+    fn unlock_file(&self, p: &str) -> LockResult<()> {
+        let r = self.backend.unlock(p)?;
+        match r.success {
+            true => Ok(()),
+            false => Err(LockError::from_git_stderr(&r.stderr, || None)),
         }
     }
 
-    fn unlock_id(&self, id: u32) -> bool {
-        let out = Command::new("cmd").args(["/C".into(), ["git lfs unlock --id ".into(), id.to_string()].join("")]).creation_flags(CREATE_NO_WINDOW).output();
-        match out {
-            Err(_) => false,
-            Ok(r) => r.status.success(),
+    fn unlock_id(&self, id: u32) -> LockResult<()> {
+        let r = self.backend.unlock_id(id)?;
+        match r.success {
+            true => Ok(()),
+            false => Err(LockError::from_git_stderr(&r.stderr, || None)),
         }
     }
-}
\ No newline at end of file
+
+    fn get_user(&self) -> String {
+        self.backend.user()
+    }
+
+    fn get_branch(&self) -> String {
+        self.backend.branch()
+    }
+
+    fn branch_state(&self, branch: &str, target: &str) -> BranchState {
+        self.backend.branch_state(branch, target)
+    }
+
+    fn get_config(&self, key: &str) -> Option<String> {
+        self.backend.config(key)
+    }
+
+    fn get_dir(&self) -> String {
+        self.backend.dir()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::fake_git_backend::{FakeGitBackend, GitCall};
+    use crate::lock::lockstore::git_backend::GitOutput;
+
+    #[test]
+    fn lock_real_file_tags_the_new_lock() {
+        let store = MonothreadLockStore::with_backend(FakeGitBackend::new("alice", "feature"));
+        store.backend().respond_locks(&[]).respond_lock("a.uasset", 4).respond_lock(".lfs-wrangler/v1/branch/4/feature", 5).respond_lock(".lfs-wrangler/v1/dir/4/dir", 6).respond_lock(".lfs-wrangler/v1/host/4/box", 7);
+        let lock = store.lock_real_file("a.uasset").expect("lock should succeed");
+        assert_eq!(lock.id, 4);
+        assert_eq!(lock.owner, "alice");
+        let calls = store.backend().calls();
+        assert_eq!(calls.len(), 5);
+        // Checking for a pending handoff comes first
+        assert_eq!(calls[0], GitCall::ListLocks);
+        assert_eq!(calls[1], GitCall::Lock("a.uasset".into()));
+        assert_eq!(calls[2], GitCall::Lock(".lfs-wrangler/v1/branch/4/feature".into()));
+        assert!(matches!(&calls[3], GitCall::Lock(p) if p.starts_with(".lfs-wrangler/v1/dir/4/")));
+        assert!(matches!(&calls[4], GitCall::Lock(p) if p.starts_with(".lfs-wrangler/v1/host/4/")));
+    }
+
+    #[test]
+    fn get_locks_applies_tags() {
+        let store = MonothreadLockStore::with_backend(FakeGitBackend::new("alice", "main"));
+        store.backend().respond_locks(&[("a.uasset", "alice", 1), (".lfs-wrangler/v1/branch/1/main", "alice", 2), ("Q1_bob___a.uasset", "bob", 3)]);
+        let locks = store.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].branch, Some("main".into()));
+        assert_eq!(locks[0].queue, vec!["bob".to_string()]);
+    }
+
+    #[test]
+    fn get_raw_locks_reports_malformed_entries() {
+        let store = MonothreadLockStore::with_backend(FakeGitBackend::default());
+        store.backend().respond(GitOutput::ok(r#"[
+            {"id": "1", "path": "My Map/Level 03.umap", "owner": {"name": "alice"}, "locked_at": "2024-01-02T03:04:05Z"},
+            {"id": "2", "owner": {"name": "bob"}}
+        ]"#));
+        assert!(matches!(store.get_raw_locks(), Err(LockError::ParseError(e)) if e.contains("no path")));
+    }
+
+    #[test]
+    fn lock_conflicts_report_the_owner() {
+        let store = MonothreadLockStore::with_backend(FakeGitBackend::new("alice", "main"));
+        store.backend()
+            .respond(GitOutput::err("Locking a.uasset failed: lock already created"))
+            .respond_locks(&[("a.uasset", "bob", 3)]);
+        assert_eq!(store.lock_file_fetch("a.uasset").unwrap_err(), LockError::AlreadyLocked { owner: "bob".into() });
+        assert_eq!(store.unlock_id(3).unwrap_err(), LockError::Other("fake git: no scripted response".into()));
+    }
+
+    #[test]
+    fn update_removes_orphaned_tags() {
+        let store = MonothreadLockStore::with_backend(FakeGitBackend::new("alice", "main"));
+        store.backend()
+            .respond_locks(&[("B7___main", "alice", 8), ("B9___main", "bob", 10)])
+            .respond_locks(&[("B7___main", "alice", 8), ("B9___main", "bob", 10)])
+            .respond(GitOutput::ok(""))
+            .respond_locks(&[("B9___main", "bob", 10)]);
+        store.update();
+        assert_eq!(store.backend().calls(), vec![GitCall::ListLocks, GitCall::ListLocks, GitCall::Unlock("B7___main".into()), GitCall::ListLocks]);
+        assert_eq!(store.backend().pending(), 0);
+    }
+}
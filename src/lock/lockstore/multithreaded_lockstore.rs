@@ -1,20 +1,29 @@
 use std::sync::mpsc;
 use std::thread;
 
+use crate::git::{self, Repo};
 use crate::lock::{lockstore::LockStore, LfsLock};
 
+use super::lockstore::UpdateReport;
 use super::monothread_lockstore::MonothreadLockStore;
+#[cfg(test)]
+use super::inmemory_lockstore::InMemoryLockStore;
+use super::{LockError, LockResult};
 
 enum Request {
-    GetLocks(mpsc::Sender<Vec<LfsLock>>),
-    Update,
-    LockFile(String, Option<mpsc::Sender<Option<LfsLock>>>),
-    UnlockFile(String, Option<mpsc::Sender<bool>>),
-    UnlockId(u32, Option<mpsc::Sender<bool>>),
+    GetRawLocks(mpsc::Sender<LockResult<Vec<LfsLock>>>),
+    GetLocks(mpsc::Sender<LockResult<Vec<LfsLock>>>),
+    Update(mpsc::Sender<UpdateReport>),
+    LockFile(String, Option<mpsc::Sender<LockResult<LfsLock>>>),
+    UnlockFile(String, Option<mpsc::Sender<LockResult<()>>>),
+    UnlockId(u32, Option<mpsc::Sender<LockResult<()>>>),
 }
 
 fn handle_request(request: Request, store: &impl LockStore) {
     match request {
+        Request::GetRawLocks(tx) => {
+            tx.send(store.get_raw_locks()).unwrap();
+        },
         Request::GetLocks(tx) => {
             tx.send(store.get_locks()).unwrap();
         },
@@ -28,8 +37,8 @@ fn handle_request(request: Request, store: &impl LockStore) {
                 }
             };
         },
-        Request::Update => {
-            store.update();
+        Request::Update(tx) => {
+            tx.send(store.update()).unwrap();
         },
         Request::UnlockFile(file, tx_opt) => {
             match tx_opt {
@@ -46,90 +55,158 @@ fn handle_request(request: Request, store: &impl LockStore) {
     }
 }
 
-fn run(chan: mpsc::Receiver<Request>) {
-    let store = MonothreadLockStore::new();
+fn run(chan: mpsc::Receiver<Request>, store: impl LockStore) {
     loop {
         match chan.recv() {
             Err(_) => return,
             Ok(request) => {
-                handle_request(request, &*store);
+                handle_request(request, &store);
             }
         }
     }
 }
 
+fn worker_gone<T>(_: T) -> LockError {
+    LockError::Other("lock store worker thread has stopped".to_string())
+}
+
 pub struct MultithreadedLockStore {
     chan: mpsc::Sender<Request>,
+    // Asking git about the repo doesn't touch the lock server, so it skips the worker
+    local: Box<dyn LockStore + Send>,
 }
 
 impl MultithreadedLockStore {
     pub fn new() -> Box<MultithreadedLockStore> {
+        MultithreadedLockStore::for_repo(Repo::current())
+    }
+
+    pub fn for_repo(repo: Repo) -> Box<MultithreadedLockStore> {
+        MultithreadedLockStore::serving(*MonothreadLockStore::for_repo(repo.clone()), MonothreadLockStore::for_repo(repo))
+    }
+
+    // Hand everything that talks to the lock server to a worker running `store`, and answer the rest with `local`
+    pub fn serving<S: LockStore + Send + 'static>(store: S, local: Box<dyn LockStore + Send>) -> Box<MultithreadedLockStore> {
         let (tx, rx) = mpsc::channel();
-        let ls = MultithreadedLockStore{
+        thread::spawn(move || run(rx, store));
+        Box::new(MultithreadedLockStore {
             chan: tx,
-        };
-        thread::spawn(move || (run(rx)));
-        Box::new(ls)
+            local,
+        })
+    }
+
+    // A store shaped like the GUI's, working an in-memory server as the given handle's user
+    #[cfg(test)]
+    pub fn over_in_memory(store: &InMemoryLockStore) -> Box<MultithreadedLockStore> {
+        let user = store.get_user();
+        MultithreadedLockStore::serving(*store.as_user(&user), store.as_user(&user))
     }
 }
 
 impl LockStore for MultithreadedLockStore {
-    fn get_raw_locks(&self) -> Vec<crate::lock::LfsLock> {
+    fn get_raw_locks(&self) -> LockResult<Vec<LfsLock>> {
         let (tx, rx) = mpsc::channel();
-        self.chan.send(Request::GetLocks(tx)).unwrap();
-        match rx.recv() {
-            Err(_) => vec![],
-            Ok(locks) => locks,
-        }
+        self.chan.send(Request::GetRawLocks(tx)).map_err(worker_gone)?;
+        rx.recv().map_err(worker_gone)?
     }
 
-    fn update(&self) {
-        match self.chan.send(Request::Update) {
-            _ => ()
-        }
+    fn get_locks(&self) -> LockResult<Vec<LfsLock>> {
+        let (tx, rx) = mpsc::channel();
+        self.chan.send(Request::GetLocks(tx)).map_err(worker_gone)?;
+        rx.recv().map_err(worker_gone)?
     }
 
-    fn lock_file_fetch(&self, p: &String) -> Option<LfsLock> {
+    fn update(&self) -> UpdateReport {
         let (tx, rx) = mpsc::channel();
-        self.chan.send(Request::LockFile(p.clone(), Some(tx))).unwrap();
-        match rx.recv() {
-            Err(_) => None,
-            Ok(r) => r
+        let report = self.chan.send(Request::Update(tx)).map_err(worker_gone).and_then(|_| rx.recv().map_err(worker_gone));
+        match report {
+            Ok(report) => report,
+            Err(e) => UpdateReport {
+                errors: vec![("Updating".to_string(), e)],
+                ..Default::default()
+            },
         }
     }
 
-    fn lock_file(&self, p: &String) -> bool {
+    fn lock_file_fetch(&self, p: &str) -> LockResult<LfsLock> {
         let (tx, rx) = mpsc::channel();
-        self.chan.send(Request::LockFile(p.clone(), Some(tx))).unwrap();
-        match rx.recv() {
-            Err(_) => false,
-            Ok(r) => r.is_some(),
-        }
+        self.chan.send(Request::LockFile(p.to_string(), Some(tx))).map_err(worker_gone)?;
+        rx.recv().map_err(worker_gone)?
     }
 
-    fn lock_file_fast(&self, p: &String) {
-        self.chan.send(Request::LockFile(p.clone(), None)).unwrap();
+    fn lock_file_fast(&self, p: &str) {
+        self.chan.send(Request::LockFile(p.to_string(), None)).unwrap();
     }
 
-    fn unlock_file(&self, p: &String) -> bool {
+    fn unlock_file(&self, p: &str) -> LockResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.chan.send(Request::UnlockFile(p.clone(), Some(tx))).unwrap();
-        match rx.recv() {
-            Err(_) => false,
-            Ok(success) => success,
-        }
+        self.chan.send(Request::UnlockFile(p.to_string(), Some(tx))).map_err(worker_gone)?;
+        rx.recv().map_err(worker_gone)?
     }
 
-    fn unlock_file_fast(&self, p: &String) {
-        self.chan.send(Request::UnlockFile(p.clone(), None)).unwrap();
+    fn unlock_file_fast(&self, p: &str) {
+        self.chan.send(Request::UnlockFile(p.to_string(), None)).unwrap();
     }
 
-    fn unlock_id(&self, id: u32) -> bool {
+    fn unlock_id(&self, id: u32) -> LockResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.chan.send(Request::UnlockId(id, Some(tx))).unwrap();
-        match rx.recv() {
-            Err(_) => false,
-            Ok(success) => success,
-        }
+        self.chan.send(Request::UnlockId(id, Some(tx))).map_err(worker_gone)?;
+        rx.recv().map_err(worker_gone)?
+    }
+
+    fn get_user(&self) -> String {
+        self.local.get_user()
+    }
+
+    fn get_branch(&self) -> String {
+        self.local.get_branch()
     }
-}
\ No newline at end of file
+
+    fn get_host(&self) -> String {
+        self.local.get_host()
+    }
+
+    fn get_dir(&self) -> String {
+        self.local.get_dir()
+    }
+
+    fn get_config(&self, key: &str) -> Option<String> {
+        self.local.get_config(key)
+    }
+
+    fn branch_state(&self, branch: &str, target: &str) -> git::BranchState {
+        self.local.branch_state(branch, target)
+    }
+
+    fn merge_target(&self) -> Option<String> {
+        self.local.merge_target()
+    }
+
+    fn records_mode(&self) -> bool {
+        self.local.records_mode()
+    }
+
+    fn records_os_user(&self) -> bool {
+        self.local.records_os_user()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_listing_keeps_the_tags() {
+        let alice = InMemoryLockStore::new("alice");
+        let store = MultithreadedLockStore::over_in_memory(&alice);
+        let lock = store.lock_real_file("a.uasset").unwrap();
+        // The file plus its branch, dir and host tags
+        let raw = store.get_raw_locks().unwrap();
+        assert_eq!(raw.len(), 4);
+        assert_eq!(raw.len(), alice.get_raw_locks().unwrap().len());
+        let locks = store.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].id, lock.id);
+        assert_eq!(locks[0].branch, Some("main".into()));
+    }
+}
@@ -1,5 +1,11 @@
+#[allow(clippy::module_inception)]
 pub mod lock;
+pub mod repopath;
 pub mod tag;
 pub mod lockstore;
+pub mod watcher;
+pub mod deadlock;
+pub mod remotes;
 
-pub use lock::*;
\ No newline at end of file
+pub use lock::*;
+pub use repopath::RepoPath;
\ No newline at end of file
@@ -0,0 +1,137 @@
+use core::fmt;
+use std::collections::BTreeMap;
+
+use crate::git;
+use crate::lock::{LfsLock, RepoPath};
+use crate::lock::lockstore::{LockResult, LockStore};
+
+// One file's locks on each of the remotes it is locked on
+#[derive(Clone, Debug, Default)]
+pub struct RemoteLocks {
+    pub file: RepoPath,
+    // remote -> the lock that remote has on the file
+    pub locks: BTreeMap<String, LfsLock>,
+}
+
+impl RemoteLocks {
+    // The remotes of `remotes` that don't have the file locked at all
+    pub fn missing_on<'a>(&self, remotes: &'a [String]) -> Vec<&'a String> {
+        remotes.iter().filter(|remote| !self.locks.contains_key(*remote)).collect()
+    }
+
+    // Locked on every remote, but not by the same person everywhere
+    pub fn owners_disagree(&self) -> bool {
+        let mut owners = self.locks.values().map(|lock| &lock.owner);
+        match owners.next() {
+            Some(first) => owners.any(|owner| owner != first),
+            None => false,
+        }
+    }
+}
+
+/* The locks of several remotes of the same repo lined up by file. Mirrors are meant to agree, so
+anything locked on some but not all of them is worth a look */
+#[derive(Debug, Default)]
+pub struct MergedView {
+    pub remotes: Vec<String>,
+    files: BTreeMap<RepoPath, RemoteLocks>,
+}
+
+impl MergedView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fetch the locks of every store, each named after the remote it talks to
+    pub fn from_stores(stores: &[(String, &dyn LockStore)]) -> LockResult<Self> {
+        let mut view = MergedView::new();
+        for (remote, store) in stores {
+            let locks = store.get_locks()?;
+            view.add(remote, locks.into_iter().filter(|lock| !git::is_lock_test(lock)).collect());
+        }
+        Ok(view)
+    }
+
+    pub fn add(&mut self, remote: &str, locks: Vec<LfsLock>) {
+        self.remotes.push(remote.to_string());
+        for lock in locks {
+            let entry = self.files.entry(lock.file.clone()).or_insert_with(|| RemoteLocks {
+                file: lock.file.clone(),
+                ..Default::default()
+            });
+            entry.locks.insert(remote.to_string(), lock);
+        }
+    }
+
+    // Every locked file, in path order
+    pub fn files(&self) -> impl Iterator<Item = &RemoteLocks> {
+        self.files.values()
+    }
+
+    // Files locked on some of the remotes but not the rest
+    pub fn mismatched(&self) -> Vec<&RemoteLocks> {
+        self.files().filter(|file| !file.missing_on(&self.remotes).is_empty()).collect()
+    }
+}
+
+// A column per remote with who holds each file there, and a warning after any file the remotes disagree on
+impl fmt::Display for MergedView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cell = |file: &RemoteLocks, remote: &String| match file.locks.get(remote) {
+            Some(lock) => format!("{} ({})", lock.owner, lock.id),
+            None => "-".to_string(),
+        };
+        let file_width = self.files().map(|file| file.file.len()).chain(["file".len()]).max().unwrap_or_default();
+        let widths: Vec<usize> = self.remotes.iter().map(|remote| {
+            self.files().map(|file| cell(file, remote).len()).chain([remote.len()]).max().unwrap_or_default()
+        }).collect();
+
+        write!(f, "{:file_width$}", "file")?;
+        for (remote, width) in self.remotes.iter().zip(&widths) {
+            write!(f, "  {:width$}", remote)?;
+        }
+        writeln!(f)?;
+        for file in self.files() {
+            write!(f, "{:file_width$}", file.file.as_str())?;
+            for (remote, width) in self.remotes.iter().zip(&widths) {
+                write!(f, "  {:width$}", cell(file, remote))?;
+            }
+            let missing = file.missing_on(&self.remotes);
+            match (missing.is_empty(), file.owners_disagree()) {
+                (false, _) => write!(f, "  <- not locked on {}", missing.iter().map(|r| r.as_str()).collect::<Vec<_>>().join(", "))?,
+                (true, true) => write!(f, "  <- held by different users")?,
+                (true, false) => (),
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    #[test]
+    fn flags_files_locked_on_only_one_remote() {
+        // Two separate servers, as a mirror would be
+        let onprem = InMemoryLockStore::new("alice");
+        let github = InMemoryLockStore::new("alice");
+        onprem.lock_file_fetch("a.uasset").unwrap();
+        github.lock_file_fetch("a.uasset").unwrap();
+        onprem.lock_file_fetch("b.uasset").unwrap();
+
+        let view = MergedView::from_stores(&[("onprem".into(), &*onprem), ("github".into(), &*github)]).unwrap();
+        assert_eq!(view.files().count(), 2);
+        let mismatched = view.mismatched();
+        assert_eq!(mismatched.len(), 1);
+        assert_eq!(mismatched[0].file, "b.uasset");
+        assert_eq!(mismatched[0].missing_on(&view.remotes), vec!["github"]);
+
+        let table = view.to_string();
+        assert_eq!(table.lines().next().unwrap().split_whitespace().collect::<Vec<_>>(), vec!["file", "onprem", "github"]);
+        assert!(table.contains("<- not locked on github"));
+        assert!(!table.lines().find(|line| line.starts_with("a.uasset")).unwrap().contains("<-"));
+    }
+}
@@ -0,0 +1,152 @@
+use core::fmt;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+
+use crate::git;
+
+// Tidy away . and .. without touching the file system, since the file needn't exist yet
+fn lexical(p: &Path) -> PathBuf {
+    let mut tidy = PathBuf::new();
+    for component in p.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                tidy.pop();
+            },
+            c => tidy.push(c),
+        }
+    }
+    tidy
+}
+
+/* A path relative to the root of the repo, with forward slashes, the way the lfs server names files.
+Anything the user hands us gets resolved to one of these before it goes anywhere near a lock */
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RepoPath(String);
+
+impl RepoPath {
+    // A path that is already relative to the repo root, e.g. straight from the lfs server
+    pub fn new(p: &str) -> Self {
+        let p = p.replace('\\', "/");
+        let mut p = p.as_str();
+        while let Some(stripped) = p.strip_prefix("./") {
+            p = stripped;
+        }
+        RepoPath(p.to_string())
+    }
+
+    /* Resolve a path relative to `cwd` (or absolute) against the repo at `root`. None if it points
+    outside the repo */
+    pub fn resolve(p: &Path, cwd: &Path, root: &Path) -> Option<Self> {
+        let abs = lexical(&cwd.join(p));
+        let rel = abs.strip_prefix(lexical(root)).ok()?;
+        let parts: Vec<String> = rel.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+        Some(RepoPath(parts.join("/")))
+    }
+
+    // A path as the user gave it, relative to wherever we were run from
+    pub fn from_user(p: &str) -> Option<Self> {
+        Self::from_user_in(p, git::repo_root()?)
+    }
+
+    // Same again for a repo other than the one we were run from
+    pub fn from_user_in(p: &str, root: PathBuf) -> Option<Self> {
+        let cwd = std::env::current_dir().ok()?;
+        // git hands back the real path of the root, so compare against the real path of the cwd
+        let cwd = cwd.canonicalize().unwrap_or(cwd);
+        let root = root.canonicalize().unwrap_or(root);
+        Self::resolve(Path::new(p), &cwd, &root)
+    }
+
+    // Where the file lives on disk
+    pub fn to_fs_path(&self, root: &Path) -> PathBuf {
+        self.0.split('/').fold(root.to_path_buf(), |path, part| path.join(part))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for RepoPath {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl fmt::Display for RepoPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<&str> for RepoPath {
+    fn from(p: &str) -> Self {
+        RepoPath::new(p)
+    }
+}
+
+impl From<String> for RepoPath {
+    fn from(p: String) -> Self {
+        RepoPath::new(&p)
+    }
+}
+
+impl From<&String> for RepoPath {
+    fn from(p: &String) -> Self {
+        RepoPath::new(p)
+    }
+}
+
+impl From<RepoPath> for String {
+    fn from(p: RepoPath) -> Self {
+        p.0
+    }
+}
+
+impl PartialEq<str> for RepoPath {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == RepoPath::new(other).0
+    }
+}
+
+impl PartialEq<&str> for RepoPath {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+impl PartialEq<String> for RepoPath {
+    fn eq(&self, other: &String) -> bool {
+        *self == *other.as_str()
+    }
+}
+
+impl PartialEq<RepoPath> for String {
+    fn eq(&self, other: &RepoPath) -> bool {
+        *other == *self.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_resolve_from_anywhere_in_the_repo() {
+        let root = Path::new("/work/game");
+        assert_eq!(RepoPath::resolve(Path::new("Rock.uasset"), Path::new("/work/game/Content/Props"), root), Some("Content/Props/Rock.uasset".into()));
+        assert_eq!(RepoPath::resolve(Path::new("../Maps/./L03.umap"), Path::new("/work/game/Content/Props"), root), Some("Content/Maps/L03.umap".into()));
+        assert_eq!(RepoPath::resolve(Path::new("/work/game/a.uasset"), Path::new("/tmp"), root), Some("a.uasset".into()));
+        assert_eq!(RepoPath::resolve(Path::new("../../other/a.uasset"), Path::new("/work/game/Content"), root), None);
+        assert_eq!(RepoPath::resolve(Path::new("./Content/a.uasset"), Path::new("."), Path::new(".")), Some("Content/a.uasset".into()));
+    }
+
+    #[test]
+    fn server_paths_compare_loosely() {
+        assert_eq!(RepoPath::new("./Content\\a.uasset"), "Content/a.uasset");
+        assert_eq!(RepoPath::new("Content/a.uasset").to_fs_path(Path::new("/work")), Path::new("/work/Content/a.uasset"));
+    }
+}
@@ -1,45 +1,52 @@
 use crate::lock::LfsLock;
-use crate::lock::tag::Tag;
+use crate::lock::tag::{encoding, Tag};
 
 use regex::Regex;
 
+const KIND: &str = "branch";
+
 pub struct BranchTag {
     branch: String,
     target_id: u32,
+    // The path we were read from, if it predates the versioned encoding
+    legacy_path: Option<String>,
 }
 
 impl BranchTag {
+    pub fn new(target_id: u32, branch: String) -> Self {
+        BranchTag {
+            branch,
+            target_id,
+            legacy_path: None,
+        }
+    }
+
     pub fn from_lock(lock: &LfsLock) -> Option<impl Tag> {
-        let re = Regex::new("B(?<id>[0-9]+)___(?<branch>.*)").unwrap();
-        match re.captures(&lock.file) {
-            None => None,
-            Some(c) => {
-                match (c.name("id"), c.name("branch")) {
-                    (Some(id), Some(branch)) => {
-                        Some(BranchTag {
-                                branch: branch.as_str().to_string(),
-                                target_id: id.as_str().parse::<u32>().unwrap(),
-                            }
-                        )
-                    },
-                    _ => None,
-                }
-            },
+        if let Some(fields) = encoding::decode(&lock.file, KIND, 2) {
+            return Some(BranchTag::new(fields[0].parse().ok()?, fields[1].clone()));
         }
+        // Pre-v1 tags looked like B12___branch
+        let re = Regex::new("^B(?<id>[0-9]+)___(?<branch>.*)$").unwrap();
+        let c = re.captures(&lock.file)?;
+        Some(BranchTag {
+            branch: c["branch"].to_string(),
+            target_id: c["id"].parse().ok()?,
+            legacy_path: Some(lock.file.to_string()),
+        })
     }
 }
 
 pub fn for_lock(lock: &LfsLock) -> BranchTag {
-    BranchTag{
-        branch: crate::git::get_branch(),
-        target_id: lock.id,
-    }
+    BranchTag::new(lock.id, crate::git::get_branch())
 }
 
 impl Tag for BranchTag {
 
     fn get_lock_string(&self) -> String {
-        ["B", self.get_target_id().to_string().as_str(), "___", self.branch.as_str()].join("")
+        match &self.legacy_path {
+            Some(path) => path.clone(),
+            None => encoding::encode(KIND, &[&self.target_id.to_string(), &self.branch]),
+        }
     }
 
     fn apply(&self, lock: &mut LfsLock) {
@@ -49,4 +56,4 @@ impl Tag for BranchTag {
     fn get_target_id(&self) -> u32 {
         self.target_id
     }
-}
\ No newline at end of file
+}
@@ -1,43 +1,60 @@
 use std::env;
 
+use crate::git;
 use crate::lock::LfsLock;
 use regex::Regex;
-use crate::lock::tag::Tag;
+use crate::lock::tag::{encoding, Tag};
+
+const KIND: &str = "dir";
 
 pub struct DirTag {
     target_id: u32,
     dir: String,
+    // The path we were read from, if it predates the versioned encoding
+    legacy_path: Option<String>,
 }
 
 
 impl DirTag {
     // build a tag from its lfs lock representation
     pub fn from_lock(lock: &LfsLock) -> Option<DirTag> {
-        let dir_re: Regex = Regex::new("D(?<id>[0-9]+)___(?<dir>.*)").unwrap();
-        match dir_re.captures(&lock.file) {
-            None => None,
-            Some(capture) => {
-                match (capture.name("id"), capture.name("dir")) {
-                    (Some(id), Some(dir)) => {
-                        Some(DirTag{
-                            target_id: id.as_str().parse::<u32>().expect("Failed to parse int"),
-                            dir: dir.as_str().to_string(),
-                        })
-                    }
-                    _ => None,
-                }
-            }
+        if let Some(fields) = encoding::decode(&lock.file, KIND, 2) {
+            return Some(DirTag {
+                target_id: fields[0].parse().ok()?,
+                dir: fields[1].clone(),
+                legacy_path: None,
+            });
         }
+        // Pre-v1 tags looked like D12___dir
+        let dir_re: Regex = Regex::new("^D(?<id>[0-9]+)___(?<dir>.*)$").unwrap();
+        let capture = dir_re.captures(&lock.file)?;
+        Some(DirTag {
+            target_id: capture["id"].parse().ok()?,
+            dir: capture["dir"].to_string(),
+            legacy_path: Some(lock.file.to_string()),
+        })
+    }
+}
+
+impl DirTag {
+    pub fn new(target_id: u32, dir: String) -> Box<DirTag> {
+        Box::new(DirTag {
+            target_id,
+            dir,
+            legacy_path: None,
+        })
     }
 }
 
+// The checkout we were run from, wherever in it we happen to be running
+pub fn local_dir() -> String {
+    let dir = git::repo_root().unwrap_or_else(|| env::current_dir().expect("cwd is mangled"));
+    dir.to_string_lossy().to_string()
+}
+
+// Tag a lock with the checkout it was taken from
 pub fn for_lock(lock: &LfsLock) -> Box<DirTag> {
-    Box::new(
-        DirTag {
-            target_id: lock.id,
-            dir: env::current_dir().expect("cwd is mangled").to_string_lossy().to_string(),
-        }
-    )
+    DirTag::new(lock.id, local_dir())
 }
 
 impl Tag for DirTag {
@@ -46,10 +63,13 @@ impl Tag for DirTag {
     }
 
     fn get_lock_string(&self) -> String {
-        ["D", self.get_target_id().to_string().as_str(), "___", self.dir.as_str()].join("")
+        match &self.legacy_path {
+            Some(path) => path.clone(),
+            None => encoding::encode(KIND, &[&self.target_id.to_string(), &self.dir]),
+        }
     }
 
     fn get_target_id(&self) -> u32 {
         self.target_id
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,104 @@
+/* Tags are stored as locks on made up paths. Those paths live under a reserved namespace, carry a
+format version, and percent-escape every field so arbitrary branch names, owners and file paths
+survive the round trip. e.g. `.lfs-wrangler/v1/queue/12/some%20user/Content%2FMap.umap` */
+
+pub const TAG_NAMESPACE: &str = ".lfs-wrangler";
+pub const TAG_VERSION: &str = "v1";
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_'
+}
+
+pub fn escape(field: &str) -> String {
+    let mut out = String::new();
+    for b in field.bytes() {
+        if is_unreserved(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+pub fn unescape(field: &str) -> Option<String> {
+    let bytes = field.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            },
+            b if is_unreserved(b) => {
+                out.push(b);
+                i += 1;
+            },
+            // Anything else can't have come out of escape()
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// Build the lock path for a tag of the given kind
+pub fn encode(kind: &str, fields: &[&str]) -> String {
+    let mut parts = vec![TAG_NAMESPACE.to_string(), TAG_VERSION.to_string(), kind.to_string()];
+    parts.extend(fields.iter().map(|f| escape(f)));
+    parts.join("/")
+}
+
+// Every lock path of the given kind starts with this
+pub fn kind_prefix(kind: &str) -> String {
+    format!("{}/{}/{}/", TAG_NAMESPACE, TAG_VERSION, kind)
+}
+
+// Whether a lock path lives in the tag namespace at all, regardless of kind or version
+pub fn is_tag_path(path: &str) -> bool {
+    path.starts_with(&format!("{}/", TAG_NAMESPACE))
+}
+
+// Pull every field back out of a lock path of the given kind, however many there are
+pub fn decode_fields(path: &str, kind: &str) -> Option<Vec<String>> {
+    let mut parts = path.split('/');
+    if parts.next()? != TAG_NAMESPACE || parts.next()? != TAG_VERSION || parts.next()? != kind {
+        return None;
+    }
+    parts.map(unescape).collect()
+}
+
+/* Pull the fields back out of a lock path. None unless the path is in our namespace, is a version we
+understand, is the requested kind and has exactly `arity` well formed fields */
+pub fn decode(path: &str, kind: &str, arity: usize) -> Option<Vec<String>> {
+    let fields = decode_fields(path, kind)?;
+    match fields.len() == arity {
+        true => Some(fields),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_awkward_fields() {
+        let fields = ["12", "we___ird_user", "Content/My Map/Level%03.umap", "ünïcode"];
+        let path = encode("queue", &fields);
+        assert!(!path["queue/".len()..].contains(' '));
+        assert_eq!(decode(&path, "queue", 4), Some(fields.iter().map(|f| f.to_string()).collect()));
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_namespace() {
+        assert_eq!(decode("D12___Content/a.uasset", "dir", 2), None);
+        assert_eq!(decode("Content/.lfs-wrangler/v1/dir/12/x", "dir", 2), None);
+        assert_eq!(decode(".lfs-wrangler/v9/dir/12/x", "dir", 2), None);
+        assert_eq!(decode(".lfs-wrangler/v1/branch/12/x", "dir", 2), None);
+        assert_eq!(decode(".lfs-wrangler/v1/dir/12/x/y", "dir", 2), None);
+        assert_eq!(decode(".lfs-wrangler/v1/dir/12/a b", "dir", 2), None);
+        assert_eq!(decode(".lfs-wrangler/v1/dir/12/%zz", "dir", 2), None);
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+
+use crate::lock::LfsLock;
+use crate::lock::lockstore::{LockError, LockResult, LockStore};
+use crate::lock::tag::{encoding, Tag};
+
+const KIND: &str = "expiry";
+
+// When a lock should be given up if its owner hasn't released it by then
+pub struct ExpiryTag {
+    target_id: u32,
+    deadline: DateTime<Utc>,
+    // Whoever set the deadline. Only the lock owner gets to set one
+    author: Option<String>,
+}
+
+impl ExpiryTag {
+    pub fn new(target_id: u32, deadline: DateTime<Utc>) -> Box<ExpiryTag> {
+        Box::new(ExpiryTag {
+            target_id,
+            deadline,
+            author: None,
+        })
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<ExpiryTag> {
+        let fields = encoding::decode(&lock.file, KIND, 2)?;
+        Some(ExpiryTag {
+            target_id: fields[0].parse().ok()?,
+            deadline: DateTime::parse_from_rfc3339(&fields[1]).ok()?.with_timezone(&Utc),
+            author: Some(lock.owner.clone()),
+        })
+    }
+}
+
+// Replace the deadline on one of the current user's locks, or clear it with None
+pub fn set_expiry(store: &dyn LockStore, id: u32, deadline: Option<DateTime<Utc>>) -> LockResult<()> {
+    let lock = store.get_lock_id(id)?.ok_or(LockError::NotFound)?;
+    if lock.owner != store.get_user() {
+        return Err(LockError::NotOwner { owner: lock.owner });
+    }
+    if let Some(old) = lock.expires_at {
+        ExpiryTag::new(id, old).delete(store);
+    }
+    if let Some(deadline) = deadline {
+        ExpiryTag::new(id, deadline).save(store);
+    }
+    Ok(())
+}
+
+// The deadline for a lock that should last `d`, or an error if that's further out than we can count
+pub fn deadline_from(d: std::time::Duration) -> LockResult<DateTime<Utc>> {
+    chrono::Duration::from_std(d).ok()
+        .and_then(|d| Utc::now().checked_add_signed(d))
+        .ok_or_else(|| LockError::Other(format!("{} is too far in the future for a deadline", humantime::format_duration(d))))
+}
+
+// Human readable time left on a lock, e.g. "1d 4h". None once the deadline has passed
+pub fn remaining(deadline: &DateTime<Utc>) -> Option<String> {
+    // Seconds are just noise on a lock that lasts days
+    let left = (*deadline - Utc::now()).to_std().ok()?;
+    Some(humantime::format_duration(std::time::Duration::from_secs(left.as_secs().max(60) / 60 * 60)).to_string())
+}
+
+// Whether the lock runs out within the hour, which is when owners get nagged about it
+pub fn expires_soon(lock: &LfsLock) -> bool {
+    match lock.expires_at {
+        Some(deadline) => deadline - Utc::now() < chrono::Duration::hours(1),
+        None => false,
+    }
+}
+
+impl Tag for ExpiryTag {
+    fn apply(&self, lock: &mut LfsLock) {
+        match &self.author {
+            Some(author) if *author != lock.owner => (),
+            _ => lock.expires_at = Some(self.deadline),
+        }
+    }
+
+    fn get_lock_string(&self) -> String {
+        encoding::encode(KIND, &[&self.target_id.to_string(), &self.deadline.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)])
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    #[test]
+    fn deadline_from_refuses_to_overflow() {
+        assert!(deadline_from(std::time::Duration::from_secs(3600)).unwrap() > Utc::now());
+        assert!(deadline_from(humantime::parse_duration("300000y").unwrap()).is_err());
+        assert!(deadline_from(std::time::Duration::MAX).is_err());
+    }
+
+    #[test]
+    fn update_releases_expired_locks() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let stale = alice.lock_real_file("a.uasset").unwrap();
+        let fresh = alice.lock_real_file("b.uasset").unwrap();
+        set_expiry(&*alice, stale.id, Some(Utc::now() - chrono::Duration::minutes(5))).unwrap();
+        set_expiry(&*alice, fresh.id, Some(Utc::now() + chrono::Duration::minutes(30))).unwrap();
+        assert!(set_expiry(&*bob, fresh.id, None).is_err());
+
+        let locks = alice.get_locks().unwrap();
+        assert!(locks.iter().all(expires_soon));
+        assert_eq!(remaining(&locks.iter().find(|lock| lock.id == stale.id).unwrap().expires_at.unwrap()), None);
+
+        // Only the owner's client gives the lock up
+        assert!(bob.update().released.is_empty());
+        assert_eq!(alice.get_locks().unwrap().len(), 2);
+        let report = alice.update();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.released.iter().map(|released| released.to_string()).collect::<Vec<_>>(), vec!["Released a.uasset: lock expired"]);
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].id, fresh.id);
+        assert_eq!(alice.get_raw_locks().unwrap().len(), 5);
+    }
+}
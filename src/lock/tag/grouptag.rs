@@ -0,0 +1,139 @@
+use crate::lock::LfsLock;
+use crate::lock::lockstore::{lockstore, LockError, LockResult, LockStore};
+use crate::lock::tag::{encoding, Tag};
+use crate::lock::tag::queuetag::{self, Enqueued};
+
+const KIND: &str = "group";
+
+// Marks a lock as one of a named set of files that were locked together and get released together
+pub struct GroupTag {
+    target_id: u32,
+    group: String,
+    // Whoever grouped the lock. Only the lock owner gets to group it
+    author: Option<String>,
+}
+
+impl GroupTag {
+    pub fn new(target_id: u32, group: String) -> Box<GroupTag> {
+        Box::new(GroupTag {
+            target_id,
+            group,
+            author: None,
+        })
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<GroupTag> {
+        let fields = encoding::decode(&lock.file, KIND, 2)?;
+        Some(GroupTag {
+            target_id: fields[0].parse().ok()?,
+            group: fields[1].clone(),
+            author: Some(lock.owner.clone()),
+        })
+    }
+}
+
+/* Lock every file under one group name, or none of them. If any file can't be locked, whatever was
+acquired so far is released again and the failing file is handed back alongside the error */
+pub fn lock_group(store: &dyn LockStore, group: &str, files: &[String]) -> Result<Vec<LfsLock>, (String, LockError)> {
+    let raw_locks = store.get_raw_locks().map_err(|e| (group.to_string(), e))?;
+    let mut acquired = vec![];
+    for file in files {
+        match store.lock_real_file_with(file, &raw_locks) {
+            Ok(lock) => acquired.push(lock),
+            Err(e) => {
+                for lock in &acquired {
+                    store.unlock_id_fast(lock.id);
+                }
+                // Don't leave the rolled back locks' branch and dir tags lying around
+                let _ = lockstore::cleanup_orphan_tags(store);
+                return Err((file.clone(), e));
+            },
+        }
+    }
+    for lock in &mut acquired {
+        GroupTag::new(lock.id, group.to_string()).tag(lock, store);
+    }
+    Ok(acquired)
+}
+
+// The locks in a group, whoever holds them
+pub fn members<'a>(locks: &'a [LfsLock], group: &str) -> Vec<&'a LfsLock> {
+    locks.iter().filter(|lock| lock.group.as_deref() == Some(group)).collect()
+}
+
+// Release every lock the current user holds in the group, handing back the files released
+pub fn release_group(store: &dyn LockStore, group: &str) -> LockResult<Vec<String>> {
+    let user = store.get_user();
+    let locks = store.get_locks()?;
+    let mine: Vec<&LfsLock> = members(&locks, group).into_iter().filter(|lock| lock.owner == user).collect();
+    if mine.is_empty() {
+        return Err(LockError::NotFound);
+    }
+    for lock in &mine {
+        store.unlock_id(lock.id)?;
+    }
+    Ok(mine.iter().map(|lock| lock.file.to_string()).collect())
+}
+
+// Queue up for every file in the group, so whoever holds it hands the whole set over
+pub fn enqueue_group(store: &dyn LockStore, group: &str) -> LockResult<Vec<(String, Enqueued)>> {
+    let locks = store.get_locks()?;
+    let files: Vec<String> = members(&locks, group).iter().map(|lock| lock.file.to_string()).collect();
+    if files.is_empty() {
+        return Err(LockError::NotFound);
+    }
+    let mut results = vec![];
+    for file in files {
+        let enqueued = queuetag::enqueue(store, &file)?;
+        results.push((file, enqueued));
+    }
+    Ok(results)
+}
+
+impl Tag for GroupTag {
+    fn apply(&self, lock: &mut LfsLock) {
+        match &self.author {
+            Some(author) if *author != lock.owner => (),
+            _ => lock.group = Some(self.group.clone()),
+        }
+    }
+
+    fn get_lock_string(&self) -> String {
+        encoding::encode(KIND, &[&self.target_id.to_string(), &self.group])
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    #[test]
+    fn groups_lock_all_or_nothing() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        bob.lock_real_file("lighting.uasset").unwrap();
+        let files = vec!["level_03.umap".to_string(), "lighting.uasset".to_string(), "rock.uasset".to_string()];
+        let (file, e) = lock_group(&*alice, "level_03", &files).unwrap_err();
+        assert_eq!(file, "lighting.uasset");
+        assert_eq!(e, LockError::AlreadyLocked { owner: "bob".into() });
+        assert!(!alice.get_raw_locks().unwrap().iter().any(|lock| lock.owner == "alice"));
+
+        bob.unlock_file("lighting.uasset").unwrap();
+        bob.update();
+        assert_eq!(lock_group(&*alice, "level_03", &files).unwrap().len(), 3);
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(members(&locks, "level_03").len(), 3);
+
+        let queued = enqueue_group(&*bob, "level_03").unwrap();
+        assert!(queued.iter().all(|(_, enqueued)| matches!(enqueued, Enqueued::Queued(1, 1))));
+        assert_eq!(release_group(&*bob, "level_03").unwrap_err(), LockError::NotFound);
+        assert_eq!(release_group(&*alice, "level_03").unwrap().len(), 3);
+        alice.update();
+        assert!(bob.get_locks().unwrap().iter().all(|lock| lock.owner == "bob"));
+    }
+}
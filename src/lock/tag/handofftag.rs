@@ -0,0 +1,210 @@
+use chrono::{DateTime, Utc};
+
+use crate::lock::{LfsLock, RepoPath};
+use crate::lock::lockstore::{LockError, LockResult, LockStore};
+use crate::lock::tag::{encoding, Tag};
+use crate::lock::tag::recordtag::RecordTag;
+
+const KIND: &str = "handoff";
+
+// How long the recipient gets to claim a handed off file before it's fair game again
+pub fn handoff_window() -> chrono::Duration {
+    chrono::Duration::hours(1)
+}
+
+/* A file its owner released straight to a named user. Held by the giver, and outlives the lock it
+points at so that the recipient can claim the file before anyone else gets to it */
+pub struct HandoffTag {
+    target_id: u32,
+    target_file: RepoPath,
+    recipient: String,
+    handed_at: DateTime<Utc>,
+}
+
+impl HandoffTag {
+    pub fn new(lock: &LfsLock, recipient: String) -> Box<HandoffTag> {
+        Box::new(HandoffTag {
+            target_id: lock.id,
+            target_file: lock.file.clone(),
+            recipient,
+            handed_at: Utc::now(),
+        })
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<HandoffTag> {
+        let fields = encoding::decode(&lock.file, KIND, 4)?;
+        Some(HandoffTag {
+            target_id: fields[0].parse().ok()?,
+            target_file: fields[1].as_str().into(),
+            recipient: fields[2].clone(),
+            handed_at: DateTime::parse_from_rfc3339(&fields[3]).ok()?.with_timezone(&Utc),
+        })
+    }
+
+    pub fn recipient(&self) -> &String {
+        &self.recipient
+    }
+
+    pub fn target_file(&self) -> &RepoPath {
+        &self.target_file
+    }
+
+    // Whether the recipient still has first dibs on the file
+    pub fn is_open(&self) -> bool {
+        Utc::now() < self.handed_at + handoff_window()
+    }
+}
+
+// Every handoff in a raw listing, including those folded into records
+pub fn all_handoffs(locks: &[LfsLock]) -> Vec<HandoffTag> {
+    let mut handoffs = vec![];
+    for lock in locks {
+        match (HandoffTag::from_lock(lock), RecordTag::from_lock(lock)) {
+            (Some(tag), _) => handoffs.push(tag),
+            (None, Some(record)) => handoffs.extend(record.members().iter().filter_map(|member| {
+                HandoffTag::from_lock(&LfsLock { file: member.into(), owner: lock.owner.clone(), ..Default::default() })
+            })),
+            (None, None) => (),
+        }
+    }
+    handoffs
+}
+
+// The latest handoff whose window is still open on a file, if any
+pub fn pending_for(locks: &[LfsLock], file: &str) -> Option<HandoffTag> {
+    all_handoffs(locks).into_iter()
+        .filter(|tag| tag.is_open() && tag.target_file == *file)
+        .max_by_key(|tag| tag.handed_at)
+}
+
+/* Release one of the current user's locks straight to `recipient`. The handoff is recorded before the
+lock goes, so there's never a moment where somebody else could take the file */
+pub fn handoff(store: &dyn LockStore, file: &String, recipient: &str) -> LockResult<()> {
+    let user = store.get_user();
+    if recipient == user {
+        return Err(LockError::Other("can't hand a lock off to yourself".to_string()));
+    }
+    let lock = store.get_locks()?.into_iter()
+        .find(|lock| lock.file == *file)
+        .ok_or(LockError::NotFound)?;
+    if lock.owner != user {
+        return Err(LockError::NotOwner { owner: lock.owner });
+    }
+    let tag = HandoffTag::new(&lock, recipient.to_string());
+    tag.save(store);
+    // Look for the very tag we saved, since older handoffs of the file may still be around
+    let saved = store.get_raw_locks().map(|locks| all_handoffs(&locks).iter().any(|t| t.get_lock_string() == tag.get_lock_string()));
+    let result = match saved {
+        Ok(true) => store.unlock_id(lock.id),
+        Ok(false) => Err(LockError::Other(format!("couldn't record the handoff of {}", lock.file))),
+        Err(e) => Err(e),
+    };
+    // We still hold the lock, so don't leave a handoff of it lying around
+    if result.is_err() {
+        tag.delete(store);
+    }
+    result
+}
+
+// Take every file handed to the current user that's still free. Returns the locks we got
+pub fn claim_handoffs(store: &dyn LockStore) -> LockResult<Vec<LfsLock>> {
+    let user = store.get_user();
+    let locks = store.get_raw_locks()?;
+    let mut claimed = vec![];
+    for tag in all_handoffs(&locks).into_iter().filter(|tag| tag.recipient == user && tag.is_open()) {
+        if locks.iter().any(|lock| lock.id == tag.target_id || lock.file == tag.target_file) {
+            continue;
+        }
+        match store.lock_real_file(&tag.target_file) {
+            Ok(lock) => claimed.push(lock),
+            // Already ours, or somebody got there once the window closed
+            Err(LockError::AlreadyLocked { .. }) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(claimed)
+}
+
+impl Tag for HandoffTag {
+    // The lock is released as soon as the handoff is saved, so there's nothing to show on it
+    fn apply(&self, _lock: &mut LfsLock) {}
+
+    fn get_lock_string(&self) -> String {
+        let handed_at = self.handed_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        encoding::encode(KIND, &[&self.target_id.to_string(), &self.target_file, &self.recipient, &handed_at])
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+
+    // The giver holds on to the handoff until the recipient has the file or the window closes
+    fn cleanup(&self, store: &dyn LockStore) -> LockResult<()> {
+        let claimed = match store.get_lock_file(&self.target_file)? {
+            Some(lock) => lock.owner == self.recipient,
+            None => false,
+        };
+        if claimed || !self.is_open() {
+            self.delete(store);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::caching_lockstore::CachingLockStore;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+    use crate::lock::lockstore::multithreaded_lockstore::MultithreadedLockStore;
+
+    #[test]
+    fn handoffs_skip_the_queue() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let carol = alice.as_user("carol");
+        let file = "a.uasset".to_string();
+        alice.lock_real_file(&file).unwrap();
+        assert_eq!(handoff(&*bob, &file, "carol").unwrap_err(), LockError::NotOwner { owner: "alice".into() });
+        handoff(&*alice, &file, "carol").unwrap();
+        alice.update();
+
+        assert_eq!(bob.lock_real_file(&file).unwrap_err(), LockError::HandedOff { recipient: "carol".into() });
+        let claimed = claim_handoffs(&*carol).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].owner, "carol");
+
+        // Once carol has it, alice's update tidies the handoff away
+        alice.update();
+        assert!(all_handoffs(&alice.get_raw_locks().unwrap()).is_empty());
+        assert!(claim_handoffs(&*carol).unwrap().is_empty());
+    }
+
+    #[test]
+    fn newest_handoff_wins() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        // An earlier handoff of the file that nobody has tidied away yet
+        HandoffTag { handed_at: Utc::now() - chrono::Duration::minutes(10), ..*HandoffTag::new(&lock, "carol".into()) }.save(&*alice);
+        handoff(&*alice, &file, "dave").unwrap();
+        assert_eq!(bob.lock_real_file(&file).unwrap_err(), LockError::HandedOff { recipient: "dave".into() });
+    }
+
+    #[test]
+    fn handoffs_work_through_the_gui_store() {
+        let server = InMemoryLockStore::new("alice");
+        // Nothing remembered, so every look at the locks goes over the worker channel
+        let gui = |user: &str| CachingLockStore::new(*MultithreadedLockStore::over_in_memory(&server.as_user(user)), std::time::Duration::ZERO);
+        let (alice, bob, carol) = (gui("alice"), gui("bob"), gui("carol"));
+        let file = "a.uasset".to_string();
+        alice.lock_real_file(&file).unwrap();
+        handoff(&*alice, &file, "carol").unwrap();
+
+        assert_eq!(bob.lock_real_file(&file).unwrap_err(), LockError::HandedOff { recipient: "carol".into() });
+        let claimed = claim_handoffs(&*carol).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].owner, "carol");
+    }
+}
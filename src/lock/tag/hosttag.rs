@@ -0,0 +1,102 @@
+use crate::lock::LfsLock;
+use crate::lock::tag::{encoding, Tag};
+
+const KIND: &str = "host";
+
+// Which workstation, and optionally which account on it, took a lock
+pub struct HostTag {
+    target_id: u32,
+    host: String,
+    os_user: Option<String>,
+}
+
+// This machine's name
+pub fn local_host() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+// Whoever is logged in to this machine, as opposed to their git or lfs identity
+pub fn local_os_user() -> Option<String> {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok().filter(|user| !user.is_empty())
+}
+
+// Something to tell a user about to release a lock that was taken on another machine
+pub fn foreign_host_warning(lock: &LfsLock, here: &str) -> Option<String> {
+    match &lock.host {
+        Some(host) if host != here => Some(format!("{} was locked on {}, any uncommitted work on it is still there", lock.file, host)),
+        _ => None,
+    }
+}
+
+impl HostTag {
+    pub fn new(target_id: u32, host: String, os_user: Option<String>) -> Box<HostTag> {
+        Box::new(HostTag {
+            target_id,
+            host,
+            os_user,
+        })
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<HostTag> {
+        let mut fields = encoding::decode_fields(&lock.file, KIND)?.into_iter();
+        let tag = HostTag {
+            target_id: fields.next()?.parse().ok()?,
+            host: fields.next()?,
+            os_user: fields.next(),
+        };
+        match fields.next() {
+            Some(_) => None,
+            None => Some(tag),
+        }
+    }
+}
+
+impl Tag for HostTag {
+    fn apply(&self, lock: &mut LfsLock) {
+        lock.host = Some(self.host.clone());
+        lock.os_user = self.os_user.clone();
+    }
+
+    fn get_lock_string(&self) -> String {
+        let id = self.target_id.to_string();
+        match &self.os_user {
+            Some(user) => encoding::encode(KIND, &[&id, &self.host, user]),
+            None => encoding::encode(KIND, &[&id, &self.host]),
+        }
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::{inmemory_lockstore::InMemoryLockStore, LockStore};
+
+    #[test]
+    fn locks_remember_their_host() {
+        let mut desktop = InMemoryLockStore::new("alice");
+        desktop.set_host("desktop");
+        let mut laptop = desktop.as_user("alice");
+        laptop.set_host("laptop");
+        desktop.lock_real_file("a.uasset").unwrap();
+
+        let lock = laptop.get_locks().unwrap().remove(0);
+        assert_eq!(lock.host, Some("desktop".into()));
+        assert!(lock.to_string().contains("; host: "));
+        assert!(foreign_host_warning(&lock, &desktop.get_host()).is_none());
+        assert!(foreign_host_warning(&lock, &laptop.get_host()).unwrap().contains("locked on desktop"));
+        // Who is logged in stays off the server unless the repo asks for it
+        assert_eq!(lock.os_user, None);
+    }
+
+    #[test]
+    fn os_user_is_recorded_on_request() {
+        let mut alice = InMemoryLockStore::new("alice");
+        alice.set_records_os_user(true);
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        assert_eq!(alice.get_lock_id(lock.id).unwrap().unwrap().os_user, local_os_user());
+    }
+}
@@ -1,6 +1,15 @@
+#[allow(clippy::module_inception)]
 pub mod tag;
+pub mod encoding;
+pub mod registry;
 pub mod dirtag;
 pub mod branchtag;
 pub mod queuetag;
+pub mod notetag;
+pub mod expirytag;
+pub mod hosttag;
+pub mod recordtag;
+pub mod grouptag;
+pub mod handofftag;
 
-pub use tag::*;
\ No newline at end of file
+pub use tag::*;
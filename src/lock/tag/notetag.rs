@@ -0,0 +1,117 @@
+use crate::lock::LfsLock;
+use crate::lock::lockstore::{LockError, LockResult, LockStore};
+use crate::lock::tag::{encoding, Tag};
+
+const KIND: &str = "note";
+
+// Why somebody is holding a lock, optionally with the ticket the work is for
+pub struct NoteTag {
+    target_id: u32,
+    note: String,
+    ticket: Option<String>,
+    // Whoever wrote the note. Only the lock owner gets to annotate their lock
+    author: Option<String>,
+}
+
+impl NoteTag {
+    pub fn new(target_id: u32, note: String, ticket: Option<String>) -> Box<NoteTag> {
+        Box::new(NoteTag {
+            target_id,
+            note,
+            ticket,
+            author: None,
+        })
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<NoteTag> {
+        let mut fields = encoding::decode_fields(&lock.file, KIND)?.into_iter();
+        let target_id = fields.next()?.parse().ok()?;
+        let note = fields.next()?;
+        let ticket = fields.next();
+        match fields.next() {
+            Some(_) => None,
+            None => Some(NoteTag {
+                target_id,
+                note,
+                ticket,
+                author: Some(lock.owner.clone()),
+            }),
+        }
+    }
+}
+
+/* Replace the note on one of the current user's locks. An empty note with no ticket just clears it,
+and a ticket of None keeps whatever ticket the lock already had */
+pub fn set_note(store: &dyn LockStore, id: u32, note: &str, ticket: Option<&str>) -> LockResult<()> {
+    let lock = store.get_lock_id(id)?.ok_or(LockError::NotFound)?;
+    if lock.owner != store.get_user() {
+        return Err(LockError::NotOwner { owner: lock.owner });
+    }
+    let ticket = match ticket {
+        Some("") => None,
+        Some(t) => Some(t.to_string()),
+        None => lock.ticket.clone(),
+    };
+    if let Some(old) = &lock.note {
+        NoteTag::new(id, old.clone(), lock.ticket.clone()).delete(store);
+    }
+    if !note.is_empty() || ticket.is_some() {
+        NoteTag::new(id, note.to_string(), ticket).save(store);
+    }
+    Ok(())
+}
+
+impl Tag for NoteTag {
+    fn apply(&self, lock: &mut LfsLock) {
+        match &self.author {
+            Some(author) if *author != lock.owner => (),
+            _ => {
+                lock.note = Some(self.note.clone());
+                lock.ticket = self.ticket.clone();
+            },
+        }
+    }
+
+    fn get_lock_string(&self) -> String {
+        let id = self.target_id.to_string();
+        match &self.ticket {
+            Some(ticket) => encoding::encode(KIND, &[&id, &self.note, ticket]),
+            None => encoding::encode(KIND, &[&id, &self.note]),
+        }
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    #[test]
+    fn notes_belong_to_the_lock_owner() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        set_note(&*alice, lock.id, "reworking LOD", Some("ART-12")).unwrap();
+        assert_eq!(set_note(&*bob, lock.id, "mine now", None).unwrap_err(), LockError::NotOwner { owner: "alice".into() });
+        // Notes smuggled in by anybody else are ignored
+        NoteTag::new(lock.id, "mine now".into(), None).save(&*bob);
+
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(locks[0].note, Some("reworking LOD".into()));
+        assert_eq!(locks[0].ticket, Some("ART-12".into()));
+        assert!(locks[0].to_string().contains("note: reworking LOD [ART-12]"));
+
+        set_note(&*alice, lock.id, "fixing/LOD 2", None).unwrap();
+        let locks = alice.get_locks().unwrap();
+        assert_eq!(locks[0].note, Some("fixing/LOD 2".into()));
+        assert_eq!(locks[0].ticket, Some("ART-12".into()));
+
+        alice.unlock_id(lock.id).unwrap();
+        alice.update();
+        assert!(!alice.get_raw_locks().unwrap().iter().any(|lock| lock.owner == "alice"));
+    }
+}
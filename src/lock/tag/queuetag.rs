@@ -1,75 +1,284 @@
-use crate::{git, lock::LfsLock};
-use super::Tag;
-use crate::lock::lockstore::LockStore;
+use chrono::{DateTime, Utc};
+
+use crate::{git, lock::LfsLock, lock::RepoPath};
+use super::{encoding, recordtag::RecordTag, Tag};
+use crate::lock::lockstore::{LockError, LockResult, LockStore};
 
 use regex::Regex;
 
+const KIND: &str = "queue";
+
 pub struct QueueTag {
     target_id: u32,
-    target_file: String,
+    target_file: RepoPath,
     queue_owner: String,
+    // When the owner joined the queue, which decides their place in it. Legacy tags don't have one
+    enqueued_at: Option<String>,
+    // The path we were read from, if it predates the versioned encoding
+    legacy_path: Option<String>,
 }
 
 pub fn for_lock(lock: &LfsLock) -> Box<QueueTag> {
-    Box::new(
-        QueueTag {
-            target_id: lock.id,
-            target_file: lock.file.clone(),
-            queue_owner: git::get_lfs_user(),
+    QueueTag::new(lock, git::get_lfs_user())
+}
+
+// Every queue tag out there, whether it's a lock of its own or part of a record
+pub fn all_queue_tags(locks: &[LfsLock]) -> Vec<QueueTag> {
+    let mut queue = vec![];
+    for lock in locks {
+        match (QueueTag::from_lock(lock), RecordTag::from_lock(lock)) {
+            (Some(tag), _) => queue.push(tag),
+            (None, Some(record)) => queue.extend(record.members().iter().filter_map(|member| {
+                QueueTag::from_lock(&LfsLock { file: member.into(), owner: lock.owner.clone(), ..Default::default() })
+            })),
+            (None, None) => (),
         }
-    )
+    }
+    queue
+}
+
+// Every queue tag pointing at target_id, head first
+pub fn queue_for(store: &dyn LockStore, target_id: u32) -> LockResult<Vec<QueueTag>> {
+    let mut queue = all_queue_tags(&store.get_raw_locks()?);
+    queue.retain(|tag| tag.target_id == target_id);
+    queue.sort_by_key(|tag| tag.sort_key());
+    Ok(queue)
+}
+
+// What enqueueing for a file ended up doing
+#[derive(Debug)]
+pub enum Enqueued {
+    // Nobody held the file, so we just took it
+    Locked(Box<LfsLock>),
+    // 1-based place in the queue and the queue's length
+    Queued(usize, usize),
+    AlreadyQueued(usize, usize),
+    // The file is ours already
+    AlreadyHeld,
+}
+
+/* Get the current user the given file next. If nobody holds it that means locking it outright, and if
+somebody grabs it while we try then we queue up behind them */
+pub fn enqueue(store: &dyn LockStore, file: &str) -> LockResult<Enqueued> {
+    let user = store.get_user();
+    let find = || -> LockResult<Option<LfsLock>> {
+        Ok(store.get_locks()?.into_iter().find(|lock| lock.file == *file))
+    };
+    let lock = match find()? {
+        Some(lock) => lock,
+        None => match store.lock_real_file(file) {
+            Ok(lock) => return Ok(Enqueued::Locked(Box::new(lock))),
+            Err(LockError::AlreadyLocked { .. }) => find()?.ok_or(LockError::NotFound)?,
+            Err(e) => return Err(e),
+        },
+    };
+    if lock.owner == user {
+        return Ok(Enqueued::AlreadyHeld);
+    }
+    if let Some(position) = lock.queue_position(&user) {
+        return Ok(Enqueued::AlreadyQueued(position, lock.queue.len()));
+    }
+    QueueTag::new(&lock, user).save(store);
+    Ok(Enqueued::Queued(lock.queue.len() + 1, lock.queue.len() + 1))
+}
+
+// Take the user out of the queue for target_id, wherever their queue tags ended up
+pub fn dequeue(store: &dyn LockStore, target_id: u32, user: &String) -> LockResult<()> {
+    for tag in queue_for(store, target_id)?.iter().filter(|tag| tag.queue_owner == *user) {
+        tag.delete(store);
+    }
+    Ok(())
 }
 
 impl QueueTag {
-    pub fn from_lock(lock: &LfsLock) -> Option<impl Tag> {
-        let re = Regex::new(r"Q(?<id>[0-9]+)_(?<owner>.+)___(?<file>.*)").expect("Regex failed to compile");
-        match re.captures(&lock.file) {
-            None => None,
-            Some(c) =>  {
-                match (c.name("id"), c.name("owner"), c.name("file")) {
-                    (Some(id), Some(owner), Some(f)) => Some(QueueTag{
-                        target_id: id.as_str().parse().expect("failed to parse int"),
-                        target_file: f.as_str().to_string(),
-                        queue_owner: owner.as_str().to_string(),
-                    }),
-                    _ => None,
-                }
+    // Enqueue an arbitrary user for the lock
+    pub fn new(lock: &LfsLock, queue_owner: String) -> Box<QueueTag> {
+        Box::new(
+            QueueTag {
+                target_id: lock.id,
+                target_file: lock.file.clone(),
+                queue_owner,
+                enqueued_at: Some(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+                legacy_path: None,
             }
+        )
+    }
+
+    pub fn from_lock(lock: &LfsLock) -> Option<QueueTag> {
+        if let Some(fields) = encoding::decode_fields(&lock.file, KIND) {
+            let mut fields = fields.into_iter();
+            let tag = QueueTag {
+                target_id: fields.next()?.parse().ok()?,
+                queue_owner: fields.next()?,
+                target_file: fields.next()?.into(),
+                enqueued_at: fields.next(),
+                legacy_path: None,
+            };
+            return match fields.next() {
+                Some(_) => None,
+                None => Some(tag),
+            };
         }
+        // Pre-v1 tags looked like Q12_owner___file, which is ambiguous if the owner contains ___
+        let re = Regex::new(r"^Q(?<id>[0-9]+)_(?<owner>.+?)___(?<file>.*)$").expect("Regex failed to compile");
+        let c = re.captures(&lock.file)?;
+        Some(QueueTag {
+            target_id: c["id"].parse().ok()?,
+            target_file: c["file"].into(),
+            queue_owner: c["owner"].to_string(),
+            enqueued_at: None,
+            legacy_path: Some(lock.file.to_string()),
+        })
+    }
+
+    pub fn owner(&self) -> &String {
+        &self.queue_owner
+    }
+
+    pub fn target_file(&self) -> &RepoPath {
+        &self.target_file
+    }
+
+    fn enqueued_at(&self) -> Option<DateTime<Utc>> {
+        self.enqueued_at.as_ref().and_then(|t| DateTime::parse_from_rfc3339(t).ok()).map(|t| t.with_timezone(&Utc))
+    }
+
+    // First come first served. Untimed legacy tags are older than anything timed, and names break ties
+    fn sort_key(&self) -> (Option<DateTime<Utc>>, String) {
+        (self.enqueued_at(), self.queue_owner.clone())
     }
 }
 
 impl Tag for QueueTag {
 
     fn get_lock_string(&self) -> String {
-        ["Q", self.target_id.to_string().as_str(), "_", self.queue_owner.as_str(), "___", self.target_file.as_str()].join("")
+        match (&self.legacy_path, &self.enqueued_at) {
+            (Some(path), _) => path.clone(),
+            (None, Some(at)) => encoding::encode(KIND, &[&self.target_id.to_string(), &self.queue_owner, &self.target_file, at]),
+            (None, None) => encoding::encode(KIND, &[&self.target_id.to_string(), &self.queue_owner, &self.target_file]),
+        }
     }
 
     fn apply(&self, lock: &mut LfsLock) {
-        lock.queue.push(self.queue_owner.clone());
+        // Somebody queued twice keeps their earliest place
+        let at = self.enqueued_at();
+        match lock.queued_at.get(&self.queue_owner) {
+            Some(existing) if *existing <= at => (),
+            _ => {
+                lock.queued_at.insert(self.queue_owner.clone(), at);
+            },
+        }
+        if !lock.queue.contains(&self.queue_owner) {
+            lock.queue.push(self.queue_owner.clone());
+        }
+        let queued_at = &lock.queued_at;
+        lock.queue.sort_by(|o1, o2| (queued_at.get(o1), o1).cmp(&(queued_at.get(o2), o2)));
     }
 
     fn get_target_id(&self) -> u32 {
         self.target_id
     }
 
-    fn cleanup(&self, store: &dyn LockStore) {
-        if self.queue_owner != git::get_lfs_user() {
-            return
+    // On failure we hang on to our place in the queue and try again next update
+    fn cleanup(&self, store: &dyn LockStore) -> LockResult<()> {
+        self.claim(store).map(|_| ())
+    }
+}
+
+impl QueueTag {
+    /* Try to take the file this tag queued for, now that its lock has gone. Only the queue's owner,
+    and only while they're at the head of it, gets anywhere. Some(lock) if we got the file */
+    pub fn claim(&self, store: &dyn LockStore) -> LockResult<Option<LfsLock>> {
+        if self.queue_owner != store.get_user() {
+            return Ok(None);
         }
-        match store.lock_real_file(&self.target_file) {
-            None => {
-                match store.get_lock_file(&self.target_file) {
-                    // Nonesense case?
-                    None => (),
-                    Some(lock) => {
-                        let new_tag = for_lock(&lock);
-                        new_tag.save(store);
-                    }
-                };
-            }
-            Some(_) => (),
+        // Only the head of the queue gets to claim the file, everyone else waits their turn
+        match queue_for(store, self.target_id)?.first() {
+            Some(head) if head.queue_owner == self.queue_owner => (),
+            _ => return Ok(None),
+        }
+        let claimed = match store.lock_real_file(&self.target_file) {
+            Ok(lock) => Some(lock),
+            // Somebody beat us to it, so queue up behind them instead, keeping our place
+            Err(LockError::AlreadyLocked { .. }) => {
+                if let Ok(Some(lock)) = store.get_lock_file(&self.target_file) {
+                    let mut new_tag = QueueTag::new(&lock, store.get_user());
+                    new_tag.enqueued_at = self.enqueued_at.clone().or(new_tag.enqueued_at);
+                    new_tag.save(store);
+                }
+                None
+            },
+            // Handed to somebody else first, so keep waiting for them to be done with it
+            Err(LockError::HandedOff { .. }) => return Ok(None),
+            Err(e) => return Err(e),
         };
-        store.unlock_file_fast(&self.get_lock_string());
+        self.delete(store);
+        Ok(claimed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    fn queued_at(lock: &LfsLock, owner: &str, at: &str) -> QueueTag {
+        QueueTag {
+            target_id: lock.id,
+            target_file: lock.file.clone(),
+            queue_owner: owner.to_string(),
+            enqueued_at: Some(at.to_string()),
+            legacy_path: None,
+        }
+    }
+
+    #[test]
+    fn queue_is_first_come_first_served() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let carol = alice.as_user("carol");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        // Bob's tag reaches the server first but carol got in line earlier
+        queued_at(&lock, "bob", "2024-01-01T11:00:00.000Z").save(&*bob);
+        queued_at(&lock, "carol", "2024-01-01T10:00:00.000Z").save(&*carol);
+        let lock = alice.get_locks().unwrap().remove(0);
+        assert_eq!(lock.queue, vec!["carol".to_string(), "bob".to_string()]);
+        assert_eq!(lock.queue_position(&"bob".to_string()), Some(2));
+
+        alice.unlock_file(&file).unwrap();
+        alice.update();
+        // Bob isn't at the head, so his client leaves the file for carol
+        bob.update();
+        assert!(bob.get_locks().unwrap().is_empty());
+        carol.update();
+        bob.update();
+
+        let locks = bob.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner, "carol");
+        assert_eq!(locks[0].queue, vec!["bob".to_string()]);
+        assert_eq!(locks[0].queued_at["bob"].unwrap().to_rfc3339(), "2024-01-01T11:00:00+00:00");
+    }
+
+    #[test]
+    fn enqueue_on_a_free_file_locks_it() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let file = "a.uasset".to_string();
+        assert!(matches!(enqueue(&*alice, &file).unwrap(), Enqueued::Locked(lock) if lock.owner == "alice"));
+        assert!(matches!(enqueue(&*alice, &file).unwrap(), Enqueued::AlreadyHeld));
+        assert!(matches!(enqueue(&*bob, &file).unwrap(), Enqueued::Queued(1, 1)));
+        assert!(matches!(enqueue(&*bob, "./a.uasset").unwrap(), Enqueued::AlreadyQueued(1, 1)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dequeue_finds_the_saved_tag() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        QueueTag::new(&lock, bob.get_user()).save(&*bob);
+        dequeue(&*bob, lock.id, &bob.get_user()).unwrap();
+        assert!(alice.get_locks().unwrap()[0].queue.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::lock::LfsLock;
+use crate::lock::lockstore::{LockResult, LockStore};
+use crate::lock::tag::{encoding, get_tag, Tag};
+
+const KIND: &str = "record";
+
+/* Every tag one user holds against a lock, folded into a single companion lock. The members are
+the lock strings the tags would otherwise have been saved under, so any Tag can live in a record */
+pub struct RecordTag {
+    target_id: u32,
+    path: String,
+    // Who holds the record, and so every tag in it
+    owner: String,
+    members: Vec<String>,
+}
+
+impl RecordTag {
+    pub fn from_lock(lock: &LfsLock) -> Option<RecordTag> {
+        let mut fields = encoding::decode_fields(&lock.file, KIND)?;
+        if fields.is_empty() {
+            return None;
+        }
+        let target_id = fields.remove(0).parse().ok()?;
+        Some(RecordTag {
+            target_id,
+            path: lock.file.to_string(),
+            owner: lock.owner.clone(),
+            members: fields,
+        })
+    }
+
+    // The lock strings of every tag in the record
+    pub fn members(&self) -> &Vec<String> {
+        &self.members
+    }
+
+    fn tags(&self) -> Vec<Box<dyn Tag>> {
+        self.members.iter()
+            .filter_map(|member| get_tag(&LfsLock { file: member.into(), owner: self.owner.clone(), ..Default::default() }))
+            .collect()
+    }
+}
+
+fn record_path(target_id: u32, members: &[String]) -> String {
+    let id = target_id.to_string();
+    let mut fields = vec![id.as_str()];
+    fields.extend(members.iter().map(|m| m.as_str()));
+    encoding::encode(KIND, &fields)
+}
+
+// The record the store's user holds against target_id, if any
+fn find<S: LockStore + ?Sized>(store: &S, target_id: u32) -> LockResult<Option<RecordTag>> {
+    let user = store.get_user();
+    Ok(store.get_raw_locks()?.iter()
+        .filter(|lock| lock.owner == user)
+        .filter_map(RecordTag::from_lock)
+        .find(|record| record.target_id == target_id))
+}
+
+/* Swap the record for one holding `members`. The new record is locked before the old one is released,
+so readers never see the metadata vanish and a failed lock leaves the old record untouched */
+fn replace<S: LockStore + ?Sized>(store: &S, target_id: u32, old: Option<RecordTag>, members: Vec<String>) -> LockResult<()> {
+    if !members.is_empty() {
+        store.lock_file(&record_path(target_id, &members))?;
+    }
+    match old {
+        Some(record) => store.unlock_file(&record.path),
+        None => Ok(()),
+    }
+}
+
+// Add tags to the user's record for target_id, creating it if need be
+pub fn add<S: LockStore + ?Sized>(store: &S, target_id: u32, tags: &[String]) -> LockResult<()> {
+    let old = find(store, target_id)?;
+    let mut members = old.as_ref().map(|record| record.members.clone()).unwrap_or_default();
+    let before = members.len();
+    for tag in tags {
+        if !members.contains(tag) {
+            members.push(tag.clone());
+        }
+    }
+    match members.len() == before {
+        true => Ok(()),
+        false => replace(store, target_id, old, members),
+    }
+}
+
+// Drop a tag from the user's record for target_id. Releases the record once it is empty
+pub fn remove<S: LockStore + ?Sized>(store: &S, target_id: u32, tag: &String) -> LockResult<()> {
+    match find(store, target_id)? {
+        Some(record) if record.members.contains(tag) => {
+            let members = record.members.iter().filter(|m| *m != tag).cloned().collect();
+            replace(store, target_id, Some(record), members)
+        },
+        _ => Ok(()),
+    }
+}
+
+impl Tag for RecordTag {
+    fn apply(&self, lock: &mut LfsLock) {
+        for tag in self.tags() {
+            tag.apply(lock);
+        }
+    }
+
+    fn get_lock_string(&self) -> String {
+        self.path.clone()
+    }
+
+    fn get_target_id(&self) -> u32 {
+        self.target_id
+    }
+
+    // Let every member clean up after itself, each of which shrinks the record
+    fn cleanup(&self, store: &dyn LockStore) -> LockResult<()> {
+        // One member failing shouldn't keep the rest around
+        let mut result = Ok(());
+        for tag in self.tags() {
+            if let Err(e) = tag.cleanup(store) {
+                result = Err(e);
+            }
+        }
+        // Outside record mode the members can't find their way back here
+        if !store.records_mode() {
+            store.unlock_file_fast(&self.path);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+    use crate::lock::lockstore::multithreaded_lockstore::MultithreadedLockStore;
+    use crate::lock::tag::notetag;
+
+    #[test]
+    fn records_are_replaced_over_the_worker_channel() {
+        let mut server = InMemoryLockStore::new("alice");
+        server.set_records_mode(true);
+        let alice = MultithreadedLockStore::over_in_memory(&server);
+        let lock = alice.lock_real_file("a.uasset").unwrap();
+        notetag::set_note(&*alice, lock.id, "retopo", None).unwrap();
+        notetag::set_note(&*alice, lock.id, "retopo, then rig", None).unwrap();
+
+        let records: Vec<RecordTag> = server.get_raw_locks().unwrap().iter().filter_map(RecordTag::from_lock).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(alice.get_locks().unwrap()[0].note, Some("retopo, then rig".into()));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{LazyLock, RwLock};
+
+use crate::lock::LfsLock;
+use crate::lock::tag::*;
+
+// Turns a lock into the tag it encodes, or None if it isn't one of ours
+pub type TagDecoder = fn(&LfsLock) -> Option<Box<dyn Tag>>;
+
+static DECODERS: LazyLock<RwLock<Vec<(String, TagDecoder)>>> = LazyLock::new(|| {
+    let builtin: Vec<(String, TagDecoder)> = vec![
+        (encoding::kind_prefix("record"), |lock| Some(Box::new(recordtag::RecordTag::from_lock(lock)?))),
+        (encoding::kind_prefix("dir"), |lock| Some(Box::new(dirtag::DirTag::from_lock(lock)?))),
+        (encoding::kind_prefix("branch"), |lock| Some(Box::new(branchtag::BranchTag::from_lock(lock)?))),
+        (encoding::kind_prefix("queue"), |lock| Some(Box::new(queuetag::QueueTag::from_lock(lock)?))),
+        (encoding::kind_prefix("note"), |lock| Some(Box::new(notetag::NoteTag::from_lock(lock)?))),
+        (encoding::kind_prefix("expiry"), |lock| Some(Box::new(expirytag::ExpiryTag::from_lock(lock)?))),
+        (encoding::kind_prefix("host"), |lock| Some(Box::new(hosttag::HostTag::from_lock(lock)?))),
+        (encoding::kind_prefix("group"), |lock| Some(Box::new(grouptag::GroupTag::from_lock(lock)?))),
+        (encoding::kind_prefix("handoff"), |lock| Some(Box::new(handofftag::HandoffTag::from_lock(lock)?))),
+        // Tags written before the versioned encoding
+        ("D".to_string(), |lock| Some(Box::new(dirtag::DirTag::from_lock(lock)?))),
+        ("B".to_string(), |lock| Some(Box::new(branchtag::BranchTag::from_lock(lock)?))),
+        ("Q".to_string(), |lock| Some(Box::new(queuetag::QueueTag::from_lock(lock)?))),
+    ];
+    RwLock::new(builtin)
+});
+
+/* Teach get_tag about a new kind of tag: every lock whose path starts with `prefix` is handed to
+`decoder`. Registering a prefix again replaces the old decoder */
+pub fn register(prefix: &str, decoder: TagDecoder) {
+    let mut decoders = DECODERS.write().unwrap();
+    decoders.retain(|(p, _)| p != prefix);
+    decoders.push((prefix.to_string(), decoder));
+}
+
+// Register a decoder for tags written with encoding::encode(kind, ..)
+pub fn register_kind(kind: &str, decoder: TagDecoder) {
+    register(&encoding::kind_prefix(kind), decoder);
+}
+
+// Try the decoders whose prefix matches, most specific first
+pub fn decode(lock: &LfsLock) -> Option<Box<dyn Tag>> {
+    let decoders = DECODERS.read().unwrap();
+    let mut candidates: Vec<&(String, TagDecoder)> = decoders.iter().filter(|(prefix, _)| lock.file.starts_with(prefix.as_str())).collect();
+    candidates.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    candidates.iter().find_map(|(_, decoder)| decoder(lock))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::{inmemory_lockstore::InMemoryLockStore, LockStore};
+
+    // What a downstream crate would write for its own metadata
+    struct ReviewerTag {
+        target_id: u32,
+        reviewer: String,
+    }
+
+    impl Tag for ReviewerTag {
+        fn apply(&self, lock: &mut LfsLock) {
+            lock.metadata.insert("reviewer".to_string(), self.reviewer.clone());
+        }
+
+        fn get_lock_string(&self) -> String {
+            encoding::encode("studio-reviewer", &[&self.target_id.to_string(), &self.reviewer])
+        }
+
+        fn get_target_id(&self) -> u32 {
+            self.target_id
+        }
+    }
+
+    #[test]
+    fn custom_tags_reach_get_locks() {
+        register_kind("studio-reviewer", |lock| {
+            let fields = encoding::decode(&lock.file, "studio-reviewer", 2)?;
+            Some(Box::new(ReviewerTag { target_id: fields[0].parse().ok()?, reviewer: fields[1].clone() }))
+        });
+        let store = InMemoryLockStore::new("alice");
+        let lock = store.lock_real_file("a.uasset").unwrap();
+        ReviewerTag { target_id: lock.id, reviewer: "carol".into() }.save(&*store);
+
+        let locks = store.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].metadata.get("reviewer"), Some(&"carol".to_string()));
+        assert!(locks[0].to_string().ends_with("; reviewer: carol"));
+    }
+
+    #[test]
+    fn unknown_kinds_are_not_tags() {
+        let lock = LfsLock { file: encoding::encode("nobody-registered-this", &["1"]).into(), ..Default::default() };
+        assert!(decode(&lock).is_none());
+    }
+}
@@ -1,7 +1,3 @@
-use branchtag::BranchTag;
-use dirtag::DirTag;
-use queuetag::QueueTag;
-
 use crate::lock::*;
 use crate::lock::tag::*;
 use crate::lock::lockstore::*;
@@ -14,12 +10,22 @@ pub trait Tag {
     // Save the relevant info to the lfs
     fn save(&self, store: &dyn LockStore)
     {
-        store.lock_file_fast(&self.get_lock_string());
+        match store.records_mode() {
+            true => {
+                let _ = recordtag::add(store, self.get_target_id(), &[self.get_lock_string()]);
+            },
+            false => store.lock_file_fast(&self.get_lock_string()),
+        }
     }
     // Delete the tag's backing lock
     fn delete(&self, store: &dyn LockStore)
     {
-        store.unlock_file_fast(&self.get_lock_string());
+        match store.records_mode() {
+            true => {
+                let _ = recordtag::remove(store, self.get_target_id(), &self.get_lock_string());
+            },
+            false => store.unlock_file_fast(&self.get_lock_string()),
+        }
     }
     // Get the id of the lock this tag is associated with
     fn get_target_id(&self) -> u32;
@@ -29,36 +35,67 @@ pub trait Tag {
         self.save(store);
     }
     // Clean up a tag that no longer points to a given lock
-    fn cleanup(&self, store: &dyn LockStore)
+    fn cleanup(&self, store: &dyn LockStore) -> LockResult<()>
     {
         self.delete(store);
+        Ok(())
     }
 }
 
-pub enum Tags {
-    Dir(DirTag),
-    Branch(BranchTag),
-    Queue(QueueTag),
-}
-
 // If a lock is a tag, then we hand back a tag. If it doesn't, None
 pub fn get_tag(lock: &LfsLock) -> Option<Box<dyn Tag>> {
-    match dirtag::DirTag::from_lock(lock) {
-        None => (),
-        Some(tag) => {
-            return Some(Box::new(tag));
-        },
-    };
-    match BranchTag::from_lock(lock) {
-        None => (),
-        Some(tag) => {
-            return Some(Box::new(tag));
-        },
-    };
-    match QueueTag::from_lock(lock) {
-        None => None,
-        Some(tag) => {
-            return Some(Box::new(tag));
-        }
+    registry::decode(lock)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use branchtag::BranchTag;
+    use queuetag::QueueTag;
+
+    fn lock_on(file: &str) -> LfsLock {
+        LfsLock::new(file.to_string(), "alice".into(), "9".into(), None)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn names_with_underscores_survive() {
+        let mut target = LfsLock { id: 3, ..lock_on("Content/odd___name_%.uasset") };
+        let saved = QueueTag::new(&target, "we___ird_user".into()).get_lock_string();
+        let tag = get_tag(&lock_on(&saved)).expect("should decode");
+        assert_eq!(tag.get_target_id(), 3);
+        tag.apply(&mut target);
+        assert_eq!(target.queue, vec!["we___ird_user".to_string()]);
+    }
+
+    #[test]
+    fn real_files_are_not_tags() {
+        assert!(get_tag(&lock_on("Content/D12___Rock.uasset")).is_none());
+        assert!(get_tag(&lock_on("Content/B1___x/Q2_a___b")).is_none());
+        assert!(get_tag(&lock_on("Content/.lfs-wrangler/v1/dir/12/x")).is_none());
+    }
+
+    #[test]
+    fn legacy_tags_still_read() {
+        let tag = get_tag(&lock_on("B12___feature/x")).expect("legacy branch tag");
+        assert_eq!(tag.get_target_id(), 12);
+        // Deleting must hit the path the tag actually lives at
+        assert_eq!(tag.get_lock_string(), "B12___feature/x");
+        let mut target = lock_on("a");
+        tag.apply(&mut target);
+        assert_eq!(target.branch, Some("feature/x".into()));
+        assert!(get_tag(&lock_on("D3___C:\\work")).is_some());
+    }
+
+    #[test]
+    fn records_apply_every_member() {
+        let mut target = LfsLock { id: 5, ..lock_on("a.uasset") };
+        let members = [BranchTag::new(5, "main".into()).get_lock_string(), QueueTag::new(&target, "bob".into()).get_lock_string()];
+        let path = encoding::encode("record", &["5", &members[0], &members[1]]);
+        let tag = get_tag(&lock_on(&path)).expect("record should decode");
+        assert_eq!(tag.get_target_id(), 5);
+        tag.apply(&mut target);
+        assert_eq!(target.branch, Some("main".into()));
+        assert_eq!(target.queue, vec!["bob".to_string()]);
+    }
+}
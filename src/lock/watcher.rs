@@ -0,0 +1,64 @@
+use crate::lock::LfsLock;
+use crate::lock::lockstore::{LockResult, LockStore};
+use crate::lock::tag::handofftag;
+use crate::lock::tag::queuetag::{self, QueueTag};
+use crate::lock::tag::Tag;
+
+/* Claim every file handed off to the current user, then every file they're queued for that nobody
+holds any more, as long as they're at the head of its queue. Returns the locks we got, so callers can
+tell the user */
+pub fn claim_queued(store: &dyn LockStore) -> LockResult<Vec<LfsLock>> {
+    let user = store.get_user();
+    let mut claimed = handofftag::claim_handoffs(store)?;
+    let locks = store.get_raw_locks()?;
+    let waiting: Vec<QueueTag> = queuetag::all_queue_tags(&locks).into_iter()
+        .filter(|tag| *tag.owner() == user && !locks.iter().any(|lock| lock.id == tag.get_target_id()))
+        .collect();
+    for tag in waiting {
+        if let Some(lock) = tag.claim(store)? {
+            claimed.push(lock);
+        }
+    }
+    Ok(claimed)
+}
+
+// The files the current user is still waiting on, whether queued for or being handed to them
+pub fn queued_files(store: &dyn LockStore) -> LockResult<Vec<String>> {
+    let user = store.get_user();
+    let locks = store.get_raw_locks()?;
+    let mut files: Vec<String> = queuetag::all_queue_tags(&locks).into_iter()
+        .filter(|tag| *tag.owner() == user)
+        .map(|tag| tag.target_file().to_string())
+        .collect();
+    files.extend(handofftag::all_handoffs(&locks).into_iter()
+        .filter(|tag| *tag.recipient() == user && tag.is_open())
+        .map(|tag| tag.target_file().to_string()));
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::inmemory_lockstore::InMemoryLockStore;
+
+    #[test]
+    fn waiters_claim_without_the_holder_running_update() {
+        let alice = InMemoryLockStore::new("alice");
+        let bob = alice.as_user("bob");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        QueueTag::new(&lock, bob.get_user()).save(&*bob);
+        assert!(claim_queued(&*bob).unwrap().is_empty());
+        assert_eq!(queued_files(&*bob).unwrap(), vec![file.clone()]);
+
+        // No update on alice's side, the lock just goes away
+        alice.unlock_id(lock.id).unwrap();
+        let claimed = claim_queued(&*bob).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].file, file);
+        assert!(queued_files(&*bob).unwrap().is_empty());
+        assert_eq!(bob.get_locks().unwrap()[0].owner, "bob");
+    }
+}
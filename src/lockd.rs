@@ -0,0 +1,362 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::lock::LfsLock;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const ANONYMOUS_USER: &str = "anonymous";
+
+// Why a lock operation was refused, mapped onto the http status the lfs api expects
+#[derive(Debug, PartialEq)]
+pub enum DbError {
+    AlreadyLocked(u32),
+    NotFound,
+    NotOwner(String),
+    Storage(String),
+}
+
+// The lock table, persisted as a json file after every change
+pub struct LockDb {
+    path: Option<PathBuf>,
+    last_id: u32,
+    locks: Vec<LfsLock>,
+}
+
+impl LockDb {
+    // A db that only lives as long as the process
+    pub fn in_memory() -> Self {
+        LockDb {
+            path: None,
+            last_id: 0,
+            locks: vec![],
+        }
+    }
+
+    // Load the db at path, starting empty if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, DbError> {
+        let mut db = LockDb::in_memory();
+        db.path = Some(path.to_path_buf());
+        if !path.exists() {
+            return Ok(db);
+        }
+        let contents = fs::read_to_string(path).map_err(|e| DbError::Storage(e.to_string()))?;
+        let json: Value = serde_json::from_str(&contents).map_err(|e| DbError::Storage(e.to_string()))?;
+        db.last_id = json["last_id"].as_u64().unwrap_or_default() as u32;
+        for entry in json["locks"].as_array().unwrap_or(&vec![]) {
+            db.locks.push(LfsLock::from_json(entry).map_err(DbError::Storage)?);
+        }
+        Ok(db)
+    }
+
+    fn save(&self) -> Result<(), DbError> {
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+        let json = json!({
+            "last_id": self.last_id,
+            "locks": self.locks.iter().map(|lock| lock.to_json()).collect::<Vec<Value>>(),
+        });
+        // Write then rename so a crash never leaves a half written table behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json.to_string()).map_err(|e| DbError::Storage(e.to_string()))?;
+        fs::rename(&tmp, path).map_err(|e| DbError::Storage(e.to_string()))
+    }
+
+    pub fn locks(&self) -> &Vec<LfsLock> {
+        &self.locks
+    }
+
+    pub fn lock(&mut self, path: &str, owner: &str) -> Result<&LfsLock, DbError> {
+        if let Some(existing) = self.locks.iter().find(|lock| lock.file == path) {
+            return Err(DbError::AlreadyLocked(existing.id));
+        }
+        self.last_id += 1;
+        self.locks.push(LfsLock {
+            file: path.into(),
+            owner: owner.to_string(),
+            id: self.last_id,
+            locked_at: Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            ..Default::default()
+        });
+        self.save()?;
+        Ok(self.locks.last().expect("lock was just pushed"))
+    }
+
+    pub fn unlock(&mut self, id: u32, user: &str, force: bool) -> Result<LfsLock, DbError> {
+        let index = self.locks.iter().position(|lock| lock.id == id).ok_or(DbError::NotFound)?;
+        if !force && self.locks[index].owner != user {
+            return Err(DbError::NotOwner(self.locks[index].owner.clone()));
+        }
+        let lock = self.locks.remove(index);
+        self.save()?;
+        Ok(lock)
+    }
+}
+
+// Pull the user out of a basic auth header. The server trusts whatever name it is handed
+fn request_user(req: &tiny_http::Request) -> String {
+    let header = req.headers().iter().find(|h| h.field.equiv("Authorization"));
+    let decoded = header
+        .and_then(|h| h.value.as_str().strip_prefix("Basic ").map(|s| s.to_string()))
+        .and_then(|token| base64::engine::general_purpose::STANDARD.decode(token).ok())
+        .and_then(|bytes| String::from_utf8(bytes).ok());
+    match decoded {
+        Some(credentials) => match credentials.split_once(':') {
+            Some((user, _)) if !user.is_empty() => user.to_string(),
+            _ => ANONYMOUS_USER.to_string(),
+        },
+        None => ANONYMOUS_USER.to_string(),
+    }
+}
+
+fn query_params(url: &str) -> Vec<(String, String)> {
+    match url.split_once('?') {
+        None => vec![],
+        Some((_, query)) => query.split('&').filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((decode_component(key)?, decode_component(value)?))
+        }).collect(),
+    }
+}
+
+fn decode_component(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+// Slice a page out of the locks, which are always kept in id order. The cursor is the id to resume from
+fn paginate<'a>(locks: impl Iterator<Item = &'a LfsLock>, cursor: Option<&str>, limit: usize) -> (Vec<Value>, Option<String>) {
+    let start = cursor.and_then(|c| c.parse::<u32>().ok()).unwrap_or(0);
+    let mut page: Vec<&LfsLock> = locks.filter(|lock| lock.id >= start).take(limit + 1).collect();
+    let next_cursor = if page.len() > limit {
+        page.pop().map(|lock| lock.id.to_string())
+    } else {
+        None
+    };
+    (page.iter().map(|lock| lock.to_json()).collect(), next_cursor)
+}
+
+// A minimal implementation of the Git LFS File Locking API, enough for git-lfs and HttpLockStore
+pub struct LockServer {
+    db: Mutex<LockDb>,
+    // Who may force unlock somebody else's lock. Nobody unless configured
+    admins: Vec<String>,
+}
+
+impl LockServer {
+    pub fn new(db: LockDb) -> Self {
+        LockServer {
+            db: Mutex::new(db),
+            admins: vec![],
+        }
+    }
+
+    // Let these users force unlock anybody's lock
+    pub fn with_admins(mut self, admins: Vec<String>) -> Self {
+        self.admins = admins;
+        self
+    }
+
+    // Serve requests until the listener is closed
+    pub fn serve(&self, server: &tiny_http::Server) {
+        for req in server.incoming_requests() {
+            self.handle(req);
+        }
+    }
+
+    pub fn handle(&self, mut req: tiny_http::Request) {
+        let user = request_user(&req);
+        let mut body = String::new();
+        let _ = req.as_reader().read_to_string(&mut body);
+        let body: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        let url = req.url().to_string();
+        let path = url.split('?').next().unwrap_or("").trim_end_matches('/').to_string();
+        let segments: Vec<&str> = path.split('/').collect();
+        // Only look at the tail of the path so the server works under any lfs.url prefix
+        let (status, response) = match (req.method(), segments.as_slice()) {
+            (tiny_http::Method::Get, [.., "locks"]) => self.list(&query_params(&url)),
+            (tiny_http::Method::Post, [.., "locks", "verify"]) => self.verify(&user, &body),
+            (tiny_http::Method::Post, [.., "locks", id, "unlock"]) => self.unlock(&user, id, &body),
+            (tiny_http::Method::Post, [.., "locks"]) => self.create(&user, &body),
+            _ => (404, json!({ "message": "not found" })),
+        };
+        let content_type = tiny_http::Header::from_bytes("Content-Type", "application/vnd.git-lfs+json").expect("static header");
+        let resp = tiny_http::Response::from_string(response.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+        let _ = req.respond(resp);
+    }
+
+    fn list(&self, params: &[(String, String)]) -> (u16, Value) {
+        let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        let limit = param("limit").and_then(|l| l.parse().ok()).unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        let db = self.db.lock().unwrap();
+        let matching = db.locks().iter().filter(|lock| {
+            param("path").is_none_or(|p| lock.file == p) && param("id").is_none_or(|id| lock.id.to_string() == id)
+        });
+        let (locks, next_cursor) = paginate(matching, param("cursor"), limit);
+        let mut response = json!({ "locks": locks });
+        if let Some(c) = next_cursor {
+            response["next_cursor"] = json!(c);
+        }
+        (200, response)
+    }
+
+    fn verify(&self, user: &str, body: &Value) -> (u16, Value) {
+        let limit = body["limit"].as_u64().map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        let db = self.db.lock().unwrap();
+        let (page, next_cursor) = paginate(db.locks().iter(), body["cursor"].as_str(), limit);
+        let (ours, theirs): (Vec<Value>, Vec<Value>) = page.into_iter().partition(|lock| lock["owner"]["name"] == user);
+        let mut response = json!({ "ours": ours, "theirs": theirs });
+        if let Some(c) = next_cursor {
+            response["next_cursor"] = json!(c);
+        }
+        (200, response)
+    }
+
+    fn create(&self, user: &str, body: &Value) -> (u16, Value) {
+        let path = match body["path"].as_str() {
+            Some(path) if !path.is_empty() => path,
+            _ => return (422, json!({ "message": "missing path" })),
+        };
+        let mut db = self.db.lock().unwrap();
+        match db.lock(path, user) {
+            Ok(lock) => (201, json!({ "lock": lock.to_json() })),
+            Err(DbError::AlreadyLocked(id)) => {
+                let existing = db.locks().iter().find(|lock| lock.id == id).map(|lock| lock.to_json());
+                (409, json!({ "lock": existing, "message": "already created lock" }))
+            },
+            Err(e) => (500, json!({ "message": format!("{:?}", e) })),
+        }
+    }
+
+    fn unlock(&self, user: &str, id: &str, body: &Value) -> (u16, Value) {
+        let id = match id.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => return (404, json!({ "message": "no such lock" })),
+        };
+        // Anyone else asking to force it just gets to unlock their own locks, like everybody does
+        let force = body["force"].as_bool().unwrap_or(false) && self.admins.iter().any(|admin| admin == user);
+        match self.db.lock().unwrap().unlock(id, user, force) {
+            Ok(lock) => (200, json!({ "lock": lock.to_json() })),
+            Err(DbError::NotFound) => (404, json!({ "message": "no such lock" })),
+            Err(DbError::NotOwner(owner)) => (403, json!({ "message": format!("lock is owned by {}", owner) })),
+            Err(e) => (500, json!({ "message": format!("{:?}", e) })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lockstore::http_lockstore::HttpLockStore;
+    use crate::lock::lockstore::{LockError, LockStore};
+    use crate::lock::tag::queuetag::QueueTag;
+    use crate::lock::tag::Tag;
+    use std::sync::Arc;
+
+
+    fn spawn_server(db: LockDb) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/info/lfs", server.server_addr().to_ip().unwrap());
+        let lockd = Arc::new(LockServer::new(db));
+        std::thread::spawn(move || lockd.serve(&server));
+        url
+    }
+
+    fn user(url: &str, name: &str) -> Box<HttpLockStore> {
+        HttpLockStore::new(url, Some((name.to_string(), "pw".to_string())))
+    }
+
+    #[test]
+    fn locks_are_exclusive_and_owned() {
+        let url = spawn_server(LockDb::in_memory());
+        let alice = user(&url, "alice");
+        let bob = user(&url, "bob");
+        let lock = alice.lock_file_fetch("Maps/Level 03.umap").unwrap();
+        assert_eq!(lock.owner, "alice");
+        assert_eq!(bob.lock_file_fetch("Maps/Level 03.umap").unwrap_err(), LockError::AlreadyLocked { owner: "alice".into() });
+        assert_eq!(bob.unlock_id(lock.id).unwrap_err(), LockError::NotOwner { owner: "alice".into() });
+        assert!(alice.unlock_file("Maps/Level 03.umap").is_ok());
+        assert_eq!(alice.unlock_id(lock.id).unwrap_err(), LockError::NotFound);
+        assert!(bob.get_raw_locks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pagination_and_verify() {
+        let url = spawn_server(LockDb::in_memory());
+        let alice = user(&url, "alice");
+        let bob = user(&url, "bob");
+        for i in 0..150 {
+            let store = if i % 2 == 0 { &alice } else { &bob };
+            store.lock_file_fetch(&format!("file{}", i)).unwrap();
+        }
+        assert_eq!(alice.get_raw_locks().unwrap().len(), 150);
+        let (ours, theirs) = alice.verify().unwrap();
+        assert_eq!(ours.len(), 75);
+        assert_eq!(theirs.len(), 75);
+    }
+
+    #[test]
+    fn queue_handoff_over_http() {
+        let url = spawn_server(LockDb::in_memory());
+        let alice = user(&url, "alice");
+        let bob = user(&url, "bob");
+        let file = "a.uasset".to_string();
+        let lock = alice.lock_real_file(&file).unwrap();
+        QueueTag::new(&lock, "bob".to_string()).save(&*bob);
+        assert!(alice.unlock_id(lock.id).is_ok());
+        alice.update();
+        bob.update();
+        let locks = bob.get_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner, "bob");
+        assert!(locks[0].queue.is_empty());
+    }
+
+    #[test]
+    fn only_admins_force_unlock() {
+        let server = LockServer::new(LockDb::in_memory()).with_admins(vec!["root".into()]);
+        server.create("alice", &json!({ "path": "a.uasset" }));
+        assert_eq!(server.unlock("bob", "1", &json!({ "force": true })).0, 403);
+        assert_eq!(server.unlock("root", "1", &json!({ "force": true })).0, 200);
+    }
+
+    #[test]
+    fn db_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("wrangler-lockd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("locks.json");
+        let _ = fs::remove_file(&path);
+        {
+            let mut db = LockDb::open(&path).unwrap();
+            db.lock("a", "alice").unwrap();
+            db.lock("b", "bob").unwrap();
+            db.unlock(1, "alice", false).unwrap();
+        }
+        let mut db = LockDb::open(&path).unwrap();
+        assert_eq!(db.locks().len(), 1);
+        assert_eq!(db.locks()[0].file, "b");
+        assert_eq!(db.lock("c", "alice").unwrap().id, 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}